@@ -1,15 +1,146 @@
 #![no_std]
+#![allow(clippy::too_many_arguments)]
 
 use soroban_sdk::{
-    contract, contracterror, contractimpl, contracttype, log, symbol_short, Env, Map, String,
-    Symbol, Vec,
+    contract, contracterror, contractimpl, contracttype, log, symbol_short, token, xdr::ToXdr,
+    Address, Bytes, BytesN, Env, IntoVal, Map, String, Symbol, Val, Vec,
 };
 
 const INVOICE_MAP: Symbol = symbol_short!("MAP");
 const INVOICE_HISTORY_MAP: Symbol = symbol_short!("HISTORY");
 
+const INVOICE_TYPE: &str = "Invoice";
+const CREDIT_NOTE_TYPE: &str = "CreditNote";
+const DEBIT_NOTE_TYPE: &str = "DebitNote";
+
+// Roughly one day of ledgers at 5s per ledger
+const DAY_IN_LEDGERS: u32 = 17280;
+const INVOICE_TTL_THRESHOLD: u32 = 30 * DAY_IN_LEDGERS;
+const INVOICE_TTL_EXTEND: u32 = 120 * DAY_IN_LEDGERS;
+
+/// Largest amount accepted in minor units; matches the range of Stellar asset balances
+const MAX_AMOUNT: i128 = i64::MAX as i128;
+const MAX_DECIMALS: u32 = 18;
+const BPS_DENOMINATOR: i128 = 10_000;
+const MAX_PII_LEN: usize = 256;
+/// Longest legacy date or amount string `migrate_storage` parses
+const MAX_DECIMAL_LEN: usize = 40;
+/// Longest mongo_id prefix a recurring template may use, leaving room for `-<n>`
+const MAX_ID_PREFIX_LEN: usize = 64;
+
+/// Ledger entries one transaction may read, counting those it also writes: the network's
+/// `txMaxReadLedgerEntries` setting, 100 on mainnet (see
+/// https://developers.stellar.org/docs/networks/resource-limits-fees). Page sizes below
+/// are derived from it.
+const TX_READ_ENTRIES: u32 = 100;
+/// Entries every call reads: the contract instance and its code
+const BASE_READ_ENTRIES: u32 = 2;
+
+/// Most ids a list query scans per call. A scan of the creation-order list reads each id and
+/// its invoice; an index scan reads the invoices and at most four index entries, the last
+/// page number and the pages the window spans.
+const MAX_PAGE_SIZE: u32 = (TX_READ_ENTRIES - BASE_READ_ENTRIES) / 2;
+
+/// Most invoices `rebuild_index` re-adds per call. Each reads its id, the invoice and its
+/// status position, on top of at most three index entries.
+const MAX_REINDEX_PAGE: u32 = (TX_READ_ENTRIES - BASE_READ_ENTRIES - 3) / 3;
+
+/// Ids per stored index page. Every page but the last is full, so appending an id rewrites
+/// only the last page.
+const INDEX_PAGE_SIZE: u32 = 32;
+
+/// Ledger entries one transaction may write: `txMaxWriteLedgerEntries`, 50 on mainnet
+const TX_WRITE_ENTRIES: u32 = 50;
+
+/// Batch sizes below divide `TX_WRITE_ENTRIES`, which runs out before the read limit, by
+/// what each item writes as measured in the tests. A new invoice writes ten entries: the
+/// invoice, its id, two history entries, its status position and the last page of its
+/// client, txn hash and three vendor detail indexes. The vendor's own index pages, the
+/// instance and the vendor's auth share four more, and the rest is left for pages filling up.
+const MAX_CREATE_BATCH: u32 = (TX_WRITE_ENTRIES - 4) / 10;
+
+/// An acknowledgement writes ten entries: the invoice, two history entries, the client's
+/// auth, the txn hash page and five for the move between status lists, plus a history
+/// snapshot when it is due. What is left over covers pages filling up.
+const MAX_ACK_BATCH: u32 = TX_WRITE_ENTRIES / 11;
+
+/// A tracking update writes the invoice, two history entries and at times a history
+/// snapshot, after the operator's auth
+const MAX_TRACKING_BATCH: u32 = (TX_WRITE_ENTRIES - 1) / 4;
+
+/// A migrated invoice writes what a new one does, plus one entry per history snapshot
+const MAX_MIGRATE_BATCH: u32 = MAX_CREATE_BATCH;
+
+/// History events between stored snapshots of an invoice, so rebuilding any past state
+/// replays fewer than this many events
+const HISTORY_SNAPSHOT_INTERVAL: u32 = 16;
+
+const DAY_IN_SECONDS: u64 = 24 * 60 * 60;
+
+/// Escrow refund timeout used until the admin configures one: 30 days
+const DEFAULT_ESCROW_TIMEOUT: u64 = 30 * DAY_IN_SECONDS;
+
 #[contracttype]
 #[derive(Clone)]
+pub enum DataKey {
+    Admin,
+    PendingAdmin,
+    Operator(Address),
+    CurrencyDecimals(String),
+    CurrencyToken(String),
+    InvoiceCount,
+    InvoiceId(u32),
+    Invoice(String),
+    History(String, u32),
+    HistoryCount(String),
+    /// Invoice state after history event `n`, kept every `HISTORY_SNAPSHOT_INTERVAL` events
+    HistorySnapshot(String, u32),
+    Payments(String),
+    Installments(String),
+    Escrow(String),
+    EscrowTimeout,
+    Listing(String),
+    Bids(String),
+    NextBidId(String),
+    Financier(Address),
+    VendorExposure(Address, Address),
+    PrivacyMode,
+    LateFee(String),
+    PaymentTerms(String),
+    ParentInvoice(String),
+    Notes(String),
+    Revision(String),
+    TemplateCount,
+    Template(u32),
+    /// Id prefix of recurring templates, mapped to the only vendor whose templates may use it
+    RecurringPrefix(String),
+    Arbiter(Address),
+    Dispute(String),
+    /// Number of the last page of an index
+    IndexTail(IndexKey),
+    IndexPage(IndexKey, u32),
+    /// Position of an invoice in an index it can be removed from
+    IndexPos(IndexKey, String),
+}
+
+/// A secondary index over invoices, named by the value it looks up
+#[contracttype]
+#[derive(Clone, PartialEq)]
+pub enum IndexKey {
+    VendorEmailHash(String),
+    VendorMobileHash(String),
+    VendorId(String),
+    Vendor(Address),
+    Client(Address),
+    /// A vendor's invoices in one status. The only index invoices move out of; the others
+    /// are append-only and stale entries are skipped when read.
+    Status(Address, InvoiceStatus),
+    /// Current and previous txn hashes; entries are never dropped when the hash moves on
+    TxnHash(String),
+}
+
+#[contracttype]
+#[derive(Clone, PartialEq)]
 pub struct Track {
     pub subject: String,
     pub status: String,
@@ -18,14 +149,452 @@ pub struct Track {
     pub event: String,
     pub to: String,
 }
+
+/// A single field change recorded in invoice history. `Created` carries the full
+/// invoice and is always the first change of an invoice's history.
+#[contracttype]
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum InvoiceChange {
+    Created(Invoice),
+    Status(InvoiceStatus),
+    Action(String),
+    TxnHash(String),
+    PreviousInvoiceHash(String),
+    Timestamp(u64),
+    Tracking(Track),
+    AmountPaid(i128),
+    Lines(Vec<LineItem>),
+    Subtotal(i128),
+    TaxTotal(i128),
+    NetAmt(i128),
+    FundReception(Address),
+    Adjustment(i128),
+    LateFee(i128),
+    Discount(i128),
+    DueDate(u64),
+    FinancingDetails(Vec<FinancingRecord>),
+    SentInvoiceDeleted(bool),
+    ReceivedInvoiceDeleted(bool),
+    DeletedComments(String),
+    /// Escrowed funds paid out to an address. Records a transfer rather than a field, so
+    /// replaying it leaves the invoice unchanged.
+    EscrowReleased(Address, i128),
+    /// Escrowed funds returned to the payer; also informational on replay
+    EscrowRefunded(Address, i128),
+    /// The invoice's dispute after the step that recorded it: reason and evidence, the
+    /// standing offer and who made it, and the arbiter. Kept off the invoice, so replaying
+    /// it leaves the invoice unchanged.
+    Dispute(Dispute),
+}
+
+/// What one mutation did to an invoice
+#[contracttype]
+#[derive(Clone)]
+pub struct HistoryEvent {
+    pub action: Symbol,
+    pub actor: Option<Address>,
+    pub changes: Vec<InvoiceChange>,
+    pub timestamp: u64,
+    pub txn_hash: String,
+}
+
+/// One link of an invoice's history chain. `digest` is
+/// `sha256(xdr(event) || prev_digest)`; the first entry chains from all zeroes.
+#[contracttype]
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub event: HistoryEvent,
+    pub prev_digest: BytesN<32>,
+    pub digest: BytesN<32>,
+}
+
+/// Outcome of `verify_history` over one stretch of a history chain. `next_cursor` is the
+/// entry to continue from, or `None` once a mismatch is found or the chain has ended.
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryCheck {
+    pub mismatch: Option<u32>,
+    pub next_cursor: Option<u32>,
+}
+
+/// Salted SHA-256 commitments to an invoice's personal data, stored instead of the
+/// plaintext. Each commitment is `sha256(salt || utf8(value))`.
+#[contracttype]
+#[derive(Clone)]
+pub struct PiiCommitments {
+    pub vendor_email: BytesN<32>,
+    pub vendor_mobile: BytesN<32>,
+    pub client_fname: BytesN<32>,
+    pub client_lname: BytesN<32>,
+    pub client_email: BytesN<32>,
+    pub client_mobile: BytesN<32>,
+    /// Reference to the encrypted PII blob held off-chain
+    pub blob_ref: String,
+}
+
+#[contracttype]
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Privacy {
+    Public,
+    Private(PiiCommitments),
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PiiField {
+    VendorEmail,
+    VendorMobile,
+    ClientFname,
+    ClientLname,
+    ClientEmail,
+    ClientMobile,
+}
+
+/// A single invoice line. Amounts are in minor units of the invoice currency.
+#[contracttype]
+#[derive(Clone, PartialEq)]
+pub struct LineItem {
+    pub description: String,
+    pub quantity: u32,
+    pub unit_price: i128,
+    pub discount: i128,
+    /// Tax rate in basis points
+    pub tax_rate: u32,
+    pub tax_amount: i128,
+}
+
+/// A payment recorded against an invoice
+#[contracttype]
+#[derive(Clone)]
+pub struct Payment {
+    pub amount: i128,
+    pub payer: Address,
+    pub timestamp: u64,
+    pub reference: String,
+}
+
+/// One tranche of an installment schedule. `paid` is filled as payments are
+/// allocated to tranches in due-date order.
+#[contracttype]
+#[derive(Clone)]
+pub struct Installment {
+    pub due_date: u64,
+    pub amount: i128,
+    pub paid: i128,
+}
+
+/// How an invoice was financed. Client payments go to `financier` until the advance and fee
+/// are repaid, and to the vendor's `fund_reception` after that.
+#[contracttype]
+#[derive(Clone, PartialEq)]
+pub struct FinancingRecord {
+    pub finance_id: String,
+    pub financier: Address,
+    /// Amount paid to the vendor up front
+    pub advance: i128,
+    /// Financier's fee, collected from client payments once the advance is repaid
+    pub fee: i128,
+    pub timestamp: u64,
+    /// Client payments passed to the financier so far, toward the advance and then the fee
+    pub repaid: i128,
+    /// Part of the advance written off when the invoice closed without repaying it
+    pub written_off: i128,
+}
+
+/// A financier allowed to finance invoices, managed by the admin
+#[contracttype]
+#[derive(Clone)]
+pub struct Financier {
+    pub address: Address,
+    pub name: String,
+    /// Hash of the off-chain KYC attestation
+    pub kyc_hash: BytesN<32>,
+    pub active: bool,
+    /// Maximum total of unrepaid advances across all invoices
+    pub max_exposure: i128,
+    /// Maximum total of unrepaid advances to a single vendor
+    pub vendor_limit: i128,
+    /// Current total of unrepaid advances
+    pub exposure: i128,
+}
+
+/// A financier's offer on a listed invoice. The advance is locked in the contract
+/// when the bid is submitted and returned if the bid is withdrawn or loses.
+#[contracttype]
+#[derive(Clone)]
+pub struct Bid {
+    pub bid_id: u32,
+    pub financier: Address,
+    /// Share of the outstanding balance advanced to the vendor, in basis points
+    pub advance_rate: u32,
+    /// Financier's fee as a share of the outstanding balance, in basis points
+    pub fee_rate: u32,
+    pub expiry: u64,
+    pub advance: i128,
+    pub fee: i128,
+}
+
+/// One page of a list query. `next_cursor` is the position to pass to the next call,
+/// or `None` once the scan has reached the end.
+#[contracttype]
+#[derive(Clone)]
+pub struct InvoicePage {
+    pub items: Vec<Invoice>,
+    pub next_cursor: Option<u32>,
+}
+
+/// One page of an invoice's history events, continued from `next_cursor` as `InvoicePage` is
+#[contracttype]
+#[derive(Clone)]
+pub struct HistoryPage {
+    pub items: Vec<HistoryEvent>,
+    pub next_cursor: Option<u32>,
+}
+
+/// Criteria for `query_invoices`. Unset fields and an empty `statuses` match everything.
+/// Date bounds are inclusive ledger timestamps; amount bounds apply to `net_amt`.
+#[contracttype]
+#[derive(Clone)]
+pub struct InvoiceFilter {
+    pub statuses: Vec<InvoiceStatus>,
+    pub vendor: Option<Address>,
+    pub client: Option<Address>,
+    pub currency: Option<String>,
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub due_from: Option<u64>,
+    pub due_to: Option<u64>,
+    /// Only invoices still open and past due at this timestamp
+    pub overdue_at: Option<u64>,
+    pub min_amount: Option<i128>,
+    pub max_amount: Option<i128>,
+    pub financed: Option<bool>,
+    pub sent_deleted: Option<bool>,
+    pub received_deleted: Option<bool>,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LateFeeKind {
+    /// `amount` in minor units per period
+    Flat,
+    /// `amount` in basis points of the invoice amount per period
+    Percentage,
+}
+
+/// Late fee charged for every full `period` seconds an invoice stays unpaid past its due date
+#[contracttype]
+#[derive(Clone)]
+pub struct LateFeeTerms {
+    pub kind: LateFeeKind,
+    pub amount: i128,
+    pub period: u64,
+}
+
+/// Early-payment terms, e.g. 2/10 net 30: `discount_rate` basis points off when the invoice
+/// is settled within `discount_period` seconds of creation, otherwise due `net_period`
+/// seconds after creation
+#[contracttype]
+#[derive(Clone)]
+pub struct PaymentTerms {
+    pub discount_rate: u32,
+    pub discount_period: u64,
+    pub net_period: u64,
+}
+
+/// Outstanding balances of a vendor's open invoices by days past due. Pages are summed
+/// by the caller; `next_cursor` works as in `InvoicePage`.
+#[contracttype]
+#[derive(Clone)]
+pub struct AgingReport {
+    pub current: i128,
+    pub days_1_30: i128,
+    pub days_31_60: i128,
+    pub days_61_90: i128,
+    pub days_over_90: i128,
+    pub next_cursor: Option<u32>,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisputeState {
+    /// The parties can trade counter-offers
+    Open,
+    /// Waiting for the admin to assign an arbiter, then for its ruling
+    Escalated,
+    Resolved,
+}
+
+/// A client's dispute of an invoice. Offers and rulings name the total the invoice should
+/// settle at.
+#[contracttype]
+#[derive(Clone)]
+pub struct Dispute {
+    pub reason_code: u32,
+    pub evidence_hash: BytesN<32>,
+    pub opened_at: u64,
+    /// Status the invoice returns to once the dispute is resolved
+    pub resume_status: InvoiceStatus,
+    pub state: DisputeState,
+    pub offer_amount: i128,
+    pub offer_by: Option<Address>,
+    pub arbiter: Option<Address>,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RecurrenceEnd {
+    Never,
+    /// Stop after this many invoices
+    AfterCount(u32),
+    /// Stop once the next issue date would be past this timestamp
+    Until(u64),
+}
+
+/// A schedule that mints a copy of `invoice` every `interval` seconds. Minted invoices are
+/// created at their scheduled time, keep the prototype's due period and get the id
+/// `<invoice.mongo_id>-<template_id>-<n>`, counting from 1. Ids of that shape under a
+/// template's prefix cannot be created directly.
+#[contracttype]
+#[derive(Clone)]
+pub struct RecurringTemplate {
+    pub invoice: Invoice,
+    pub interval: u64,
+    pub end: RecurrenceEnd,
+    pub next_issue: u64,
+    pub issued: u32,
+    pub active: bool,
+}
+
+/// Outcome of one item of a batch call; `error` carries the `InvoiceError` code of an
+/// item that was skipped
+#[contracttype]
+#[derive(Clone)]
+pub struct BatchResult {
+    pub mongo_id: String,
+    pub error: Option<u32>,
+}
+
+/// One acknowledgement for `ack_invoices`
+#[contracttype]
+#[derive(Clone)]
+pub struct AckRequest {
+    pub mongo_id: String,
+    pub revision: u32,
+    pub action: String,
+    pub txn_hash: String,
+}
+
+/// One tracking update for `update_tracking_batch`
+#[contracttype]
+#[derive(Clone)]
+pub struct TrackingUpdate {
+    pub mongo_id: String,
+    pub tracking: Track,
+}
+
+/// Corrections for `amend_invoice`. Unset fields keep their current value; totals are
+/// recomputed from `lines`.
+#[contracttype]
+#[derive(Clone)]
+pub struct InvoicePatch {
+    pub due_date: Option<u64>,
+    pub lines: Option<Vec<LineItem>>,
+    pub fund_reception: Option<Address>,
+}
+
+/// Funds held by the contract for an escrow-mode invoice
+#[contracttype]
+#[derive(Clone)]
+pub struct Escrow {
+    pub balance: i128,
+    /// Ledger timestamp of the first escrowed payment
+    pub funded_at: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvoiceStatus {
+    Issued,
+    Acknowledged,
+    Financed,
+    PartiallyPaid,
+    Paid,
+    PaymentConfirmed,
+    Rejected,
+    Voided,
+    Refunded,
+    Disputed,
+    /// Credit or debit note, applied to its parent invoice when created
+    Applied,
+    /// Settled in full by credit notes, with nothing left for the client to pay
+    Credited,
+}
+
+/// Every allowed `(from, to)` status change. Entry points go through
+/// `InvoiceContract::transition`, which rejects anything not listed here.
+const INVOICE_TRANSITIONS: &[(InvoiceStatus, InvoiceStatus)] = &[
+    (InvoiceStatus::Issued, InvoiceStatus::Acknowledged),
+    (InvoiceStatus::Issued, InvoiceStatus::Rejected),
+    (InvoiceStatus::Issued, InvoiceStatus::Voided),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Financed),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::PartiallyPaid),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Paid),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Rejected),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Voided),
+    (InvoiceStatus::Financed, InvoiceStatus::PartiallyPaid),
+    (InvoiceStatus::Financed, InvoiceStatus::Paid),
+    (InvoiceStatus::PartiallyPaid, InvoiceStatus::PartiallyPaid),
+    (InvoiceStatus::PartiallyPaid, InvoiceStatus::Paid),
+    (InvoiceStatus::Paid, InvoiceStatus::PaymentConfirmed),
+    (InvoiceStatus::PartiallyPaid, InvoiceStatus::Refunded),
+    (InvoiceStatus::Paid, InvoiceStatus::Refunded),
+    (InvoiceStatus::Issued, InvoiceStatus::Disputed),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Disputed),
+    (InvoiceStatus::PartiallyPaid, InvoiceStatus::Disputed),
+    (InvoiceStatus::Paid, InvoiceStatus::Disputed),
+    (InvoiceStatus::Disputed, InvoiceStatus::Issued),
+    (InvoiceStatus::Disputed, InvoiceStatus::Acknowledged),
+    (InvoiceStatus::Disputed, InvoiceStatus::PartiallyPaid),
+    (InvoiceStatus::Disputed, InvoiceStatus::Paid),
+    (InvoiceStatus::Disputed, InvoiceStatus::PaymentConfirmed),
+    (InvoiceStatus::Disputed, InvoiceStatus::Refunded),
+    (InvoiceStatus::Issued, InvoiceStatus::Credited),
+    (InvoiceStatus::Acknowledged, InvoiceStatus::Credited),
+    (InvoiceStatus::PartiallyPaid, InvoiceStatus::Credited),
+];
+
+/// Statuses after which no more payments reach the invoice. Moving into one writes off any
+/// advance still unrepaid, so escrow held for a financed invoice must be released first.
+const CLOSED_STATUSES: &[InvoiceStatus] = &[
+    InvoiceStatus::PaymentConfirmed,
+    InvoiceStatus::Rejected,
+    InvoiceStatus::Voided,
+    InvoiceStatus::Refunded,
+    InvoiceStatus::Credited,
+];
+
 #[contracterror]
-#[derive( Clone)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InvoiceError {
     NotFound = 4004,
     InvoiceAlreadyExists = 1002,
     InvoiceNotAcknowledged = 1003,
     InvoiceAlreadyDeleted = 1004,
-    AlreadyFinanced=1005,
+    AlreadyFinanced = 1005,
+    TokenSettlementRequired = 1007,
+    EscrowLocked = 1008,
+    NotListed = 1009,
+    BidExpired = 1010,
+    FinancierSuspended = 1011,
+    ExposureLimitExceeded = 1012,
+    ArbiterAlreadyExists = 1013,
+    NotDue = 1014,
+    RevisionMismatch = 1015,
+    ArbiterNotFound = 4006,
+    FinancierNotFound = 4005,
     InvoiceAcknowledged = 2001,
     InvoiceFinanced = 2002,
     InvoicePaid = 2003,
@@ -33,11 +602,81 @@ pub enum InvoiceError {
     InvoiceVoided = 2005,
     InvoicePaymentConfirmed = 2006,
     InvalidInput = 304,
+    InvalidAmount = 305,
+    UnsupportedCurrency = 306,
+    TotalsMismatch = 307,
+    PlaintextPiiNotAllowed = 308,
+    InvalidTransition = 2000,
+    Unauthorized = 4001,
+    NoPendingAdmin = 4002,
+    OperatorNotFound = 4003,
+    OperatorAlreadyExists = 1006,
+    AdminNotSet = 4007,
 }
 
 #[contracttype]
 #[derive(Clone)]
 pub struct Invoice {
+    pub inv_type: String,
+    pub vendor: Address,
+    pub client: Address,
+    pub vendor_id: String,
+    pub mongo_id: String,
+    /// Ledger timestamp, in seconds
+    pub creation_date: u64,
+    pub vendor_email: String,
+    pub action: String,
+    pub status: InvoiceStatus,
+    pub financing_details: Vec<FinancingRecord>,
+    /// Lookup hash of the vendor's email; empty on private invoices
+    pub vendor_email_hash: String,
+    /// Lookup hash of the vendor's mobile; empty on private invoices
+    pub vendor_mobile_hash: String,
+    pub vendor_mobile: String,
+    pub client_fname: String,
+    pub client_lname: String,
+    pub vendor_name: String,
+    pub client_email: String,
+    pub client_mobile: String,
+    pub currency: String,
+    pub fund_reception: Address,
+    pub lines: Vec<LineItem>,
+    /// Sum of line amounts after discounts, before tax
+    pub subtotal: i128,
+    pub tax_total: i128,
+    /// Grand total in minor units of `currency`
+    pub net_amt: i128,
+    /// Signed change to `net_amt` from credit and debit notes and settled disputes;
+    /// `net_amt + adjustment` is the adjusted invoice amount
+    pub adjustment: i128,
+    /// Sum of all recorded payments
+    pub amount_paid: i128,
+    /// Late fees accrued as of the last payment; `outstanding` adds any accrued since
+    pub late_fee: i128,
+    /// Early-payment discount granted when the invoice was settled inside the discount window
+    pub discount: i128,
+    /// Hold payments in the contract until the client confirms delivery
+    pub escrow: bool,
+    /// Decimals of `currency` at creation time
+    pub decimals: u32,
+    pub sent_invoice_deleted: bool,
+    pub received_invoice_deleted: bool,
+    pub timestamp: u64,
+    pub previous_invoice_hash: String,
+    pub txn_hash: String,
+    /// Ledger timestamp, in seconds
+    pub due_date: u64,
+    pub deleted_comments: String,
+    pub tracking: Track,
+    /// Private invoices leave their plaintext PII fields empty
+    pub privacy: Privacy,
+}
+
+/// Invoice layout of the original instance-storage version, kept to decode its `MAP` and
+/// `HISTORY` entries during `migrate_storage`
+#[contracttype]
+#[derive(Clone)]
+pub struct LegacyInvoice {
     pub inv_type: String,
     pub vendor_id: String,
     pub mongo_id: String,
@@ -73,182 +712,3096 @@ pub struct Invoice {
     pub tracking: Track,
 }
 
+/// On-chain parties of a legacy invoice, which only stored their contact details
+#[contracttype]
+#[derive(Clone)]
+pub struct LegacyParties {
+    pub mongo_id: String,
+    pub vendor: Address,
+    pub client: Address,
+}
+
 #[contract]
 pub struct InvoiceContract;
 
 #[contractimpl]
 impl InvoiceContract {
-    fn get_invoice_storage(env: &Env) -> Map<String, Invoice> {
+    /// Set the contract admin once, at deploy time
+    pub fn __constructor(env: Env, admin: Address) {
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.events().publish((symbol_short!("Init"),), admin);
+    }
+
+    /// Replace the contract code with the uploaded wasm `new_wasm_hash`, keeping its storage.
+    /// Only the admin may upgrade; run `migrate_storage` afterwards if the new code needs it.
+    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        env.deployer()
+            .update_current_contract_wasm(new_wasm_hash.clone());
+        env.events()
+            .publish((symbol_short!("Upgraded"),), new_wasm_hash);
+        Ok(String::from_str(&env, "Contract upgraded"))
+    }
+
+    fn get_admin(env: &Env) -> Result<Address, InvoiceError> {
+        match env.storage().instance().get(&DataKey::Admin) {
+            Some(admin) => Ok(admin),
+            None => {
+                log!(env, "Error: No admin is set");
+                Err(InvoiceError::AdminNotSet)
+            }
+        }
+    }
+
+    fn require_admin(env: &Env) -> Result<Address, InvoiceError> {
+        let admin = Self::get_admin(env)?;
+        admin.require_auth();
+        Ok(admin)
+    }
+
+    fn is_operator_stored(env: &Env, operator: &Address) -> bool {
         env.storage()
             .instance()
-            .get(&INVOICE_MAP)
-            .unwrap_or(Map::new(env))
+            .has(&DataKey::Operator(operator.clone()))
+    }
+
+    fn require_operator(env: &Env, operator: &Address) -> Result<(), InvoiceError> {
+        operator.require_auth();
+        if !Self::is_operator_stored(env, operator) {
+            log!(
+                env,
+                "Error: {} is not a registered operator",
+                operator.clone()
+            );
+            return Err(InvoiceError::Unauthorized);
+        }
+        Ok(())
     }
 
-    fn get_history_storage(env: &Env) -> Map<String, Vec<Invoice>> {
+    /// Propose a new admin. The handover completes when the new admin calls `accept_admin`.
+    pub fn propose_admin(env: Env, new_admin: Address) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
         env.storage()
             .instance()
-            .get(&INVOICE_HISTORY_MAP)
-            .unwrap_or(Map::new(env))
-    }
-    /// Save the invoice map back to storage
-    fn save_invoice_storage(env: &Env, storage_map: &Map<String, Invoice>) {
-        env.storage().instance().set(&INVOICE_MAP, storage_map);
+            .set(&DataKey::PendingAdmin, &new_admin);
         env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Admin handover proposed to {}", new_admin.clone());
+        env.events()
+            .publish((symbol_short!("AdmPropos"),), new_admin);
+        Ok(String::from_str(&env, "Admin handover proposed"))
     }
 
-    fn save_history_storage(env: &Env, invoice: Invoice) {
-        let mongo_id = invoice.mongo_id.clone(); 
-        let mut history_state = Self::get_history_storage(env);
+    /// Accept a pending admin handover. Must be authorized by the proposed admin.
+    pub fn accept_admin(env: Env) -> Result<String, InvoiceError> {
+        let pending: Address = match env.storage().instance().get(&DataKey::PendingAdmin) {
+            Some(pending) => pending,
+            None => {
+                log!(&env, "Error: No admin handover is pending");
+                return Err(InvoiceError::NoPendingAdmin);
+            }
+        };
+        pending.require_auth();
 
-        let mut history = history_state.get(mongo_id.clone()).unwrap_or(Vec::new(env));
+        let previous = Self::get_admin(&env)?;
+        env.storage().instance().set(&DataKey::Admin, &pending);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+        env.storage().instance().extend_ttl(1000, 5000);
 
-        history.push_back(invoice.clone());
+        log!(&env, "Admin changed to {}", pending.clone());
+        env.events()
+            .publish((symbol_short!("AdmAccept"), previous), pending);
+        Ok(String::from_str(&env, "Admin handover accepted"))
+    }
 
-        history_state.set(mongo_id.clone(), history);
+    /// Add a backend service account to the operator allow-list
+    pub fn add_operator(env: Env, operator: Address) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
 
+        if Self::is_operator_stored(&env, &operator) {
+            log!(&env, "Error: {} is already an operator", operator.clone());
+            return Err(InvoiceError::OperatorAlreadyExists);
+        }
         env.storage()
             .instance()
-            .set(&INVOICE_HISTORY_MAP, &history_state);
-
+            .set(&DataKey::Operator(operator.clone()), &true);
         env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Operator {} added", operator.clone());
+        env.events().publish((symbol_short!("OpAdded"),), operator);
+        Ok(String::from_str(&env, "Operator added"))
     }
 
-    fn check_invoice_status(
-        invoice: Invoice,
-        ack: bool,
-        finance: bool,
-        paid: bool,
-        reject: bool,
-        void: bool,
-        confirm: bool,
-        finance_check: bool,
-        ack_check: bool,
-        paid_check: bool,
-    ) -> Option<InvoiceError> {
-        if ack_check != true {
-            if invoice.ack != ack {
-                return Some(InvoiceError::InvoiceAcknowledged);
-            }
-        }
-        if finance_check != true {
-            if invoice.finance != finance {
-                return Some(InvoiceError::InvoiceFinanced);
-            }
-        }
-        if paid_check != true {}
-        {
-            if invoice.paid != paid {
-                return Some(InvoiceError::InvoicePaid);
-            }
-        }
-        if invoice.rejected != reject {
-            return Some(InvoiceError::InvoiceRejected);
-        }
-        if invoice.voided != void {
-            return Some(InvoiceError::InvoiceVoided);
-        }
-        if invoice.payment_confirmation != confirm {
-            return Some(InvoiceError::InvoicePaymentConfirmed);
+    /// Remove a backend service account from the operator allow-list
+    pub fn remove_operator(env: Env, operator: Address) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if !Self::is_operator_stored(&env, &operator) {
+            log!(&env, "Error: {} is not an operator", operator.clone());
+            return Err(InvoiceError::OperatorNotFound);
         }
-        None
+        env.storage()
+            .instance()
+            .remove(&DataKey::Operator(operator.clone()));
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Operator {} removed", operator.clone());
+        env.events()
+            .publish((symbol_short!("OpRemoved"),), operator);
+        Ok(String::from_str(&env, "Operator removed"))
     }
 
-    /// Add or update an invoice
-    pub fn create_invoice(env: Env, invoice_input: Invoice) -> Result<String, InvoiceError> {
-        if invoice_input.mongo_id.is_empty()
-            || invoice_input.vendor_id.is_empty()
-            || invoice_input.action.is_empty()
-            || invoice_input.creation_date.is_empty()
-            || invoice_input.vendor_email.is_empty()
-            || invoice_input.vendor_email_hash.is_empty()
-            || invoice_input.vendor_mobile_hash.is_empty()
-            || invoice_input.vendor_mobile.is_empty()
-            || invoice_input.client_fname.is_empty()
-            || invoice_input.client_lname.is_empty()
-            || invoice_input.vendor_name.is_empty()
-            || invoice_input.client_email.is_empty()
-            || invoice_input.client_mobile.is_empty()
-            || invoice_input.currency.is_empty()
-            || invoice_input.fund_reception.is_empty()
-            || invoice_input.lines.is_empty()
-            || invoice_input.net_amt.is_empty()
-            || invoice_input.txn_hash.is_empty()
-            || invoice_input.due_date.is_empty()
-        {
-            log!(&env, "Error: One or more input fields are empty");
-            return Err(InvoiceError::InvalidInput);
+    /// Allow `arbiter` to rule on escalated disputes
+    pub fn add_arbiter(env: Env, arbiter: Address) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if Self::is_arbiter(&env, &arbiter) {
+            log!(&env, "Error: {} is already an arbiter", arbiter.clone());
+            return Err(InvoiceError::ArbiterAlreadyExists);
         }
-        let mut state = Self::get_invoice_storage(&env);
+        env.storage()
+            .instance()
+            .set(&DataKey::Arbiter(arbiter.clone()), &true);
+        env.storage().instance().extend_ttl(1000, 5000);
 
-        if state.contains_key(invoice_input.mongo_id.clone()) {
-            log!(
-                &env,
-                "Error: Invoice with mongo_id {} already exists",
-                invoice_input.mongo_id
-            );
-            return Err(InvoiceError::InvoiceAlreadyExists);
+        log!(&env, "Arbiter {} added", arbiter.clone());
+        env.events().publish((symbol_short!("ArbAdded"),), arbiter);
+        Ok(String::from_str(&env, "Arbiter added"))
+    }
+
+    /// Stop `arbiter` from taking new disputes. Disputes already escalated to it stay with it.
+    pub fn remove_arbiter(env: Env, arbiter: Address) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if !Self::is_arbiter(&env, &arbiter) {
+            log!(&env, "Error: {} is not an arbiter", arbiter.clone());
+            return Err(InvoiceError::ArbiterNotFound);
         }
+        env.storage()
+            .instance()
+            .remove(&DataKey::Arbiter(arbiter.clone()));
+        env.storage().instance().extend_ttl(1000, 5000);
 
-        let invoice = Invoice {
-            mongo_id: invoice_input.mongo_id,
-            inv_type: String::from_str(&env, "Invoice"),
-            vendor_id: invoice_input.vendor_id,
-            creation_date: invoice_input.creation_date,
-            vendor_email: invoice_input.vendor_email,
-            action: invoice_input.action,
-            ack: false,
-            finance: false,
-            financing_details: Vec::new(&env),
-            vendor_email_hash: invoice_input.vendor_email_hash,
-            vendor_mobile_hash: invoice_input.vendor_mobile_hash,
-            vendor_mobile: invoice_input.vendor_mobile,
-            client_fname: invoice_input.client_fname,
-            client_lname: invoice_input.client_lname,
-            vendor_name: invoice_input.vendor_name,
-            client_email: invoice_input.client_email,
-            client_mobile: invoice_input.client_mobile,
-            currency: invoice_input.currency,
-            fund_reception: invoice_input.fund_reception,
-            lines: invoice_input.lines,
-            net_amt: invoice_input.net_amt,
-            paid: false,
-            rejected: false,
-            voided: false,
-            sent_invoice_deleted: false,
-            received_invoice_deleted: false,
-            // timestamp: invoice_input.timestamp,
-            timestamp: env.ledger().timestamp(),
-            previous_invoice_hash: String::from_str(&env, ""),
-            txn_hash: invoice_input.txn_hash,
-            due_date: invoice_input.due_date,
-            deleted_comments: String::from_str(&env, ""),
-            payment_confirmation: false,
-            tracking: Track {
-                subject: String::from_str(&env, ""),
-                status: String::from_str(&env, ""),
-                msg_id: String::from_str(&env, ""),
-                api_key_id: String::from_str(&env, ""),
-                event: String::from_str(&env, ""),
-                to: String::from_str(&env, ""),
+        log!(&env, "Arbiter {} removed", arbiter.clone());
+        env.events().publish((symbol_short!("ArbRemove"),), arbiter);
+        Ok(String::from_str(&env, "Arbiter removed"))
+    }
+
+    fn is_arbiter(env: &Env, arbiter: &Address) -> bool {
+        env.storage()
+            .instance()
+            .get(&DataKey::Arbiter(arbiter.clone()))
+            .unwrap_or(false)
+    }
+
+    /// Turn privacy mode on or off. While it is on, new invoices must carry PII commitments
+    /// instead of plaintext personal data.
+    pub fn set_privacy_mode(env: Env, enabled: bool) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        env.storage()
+            .instance()
+            .set(&DataKey::PrivacyMode, &enabled);
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Privacy mode set to {}", enabled);
+        env.events().publish((symbol_short!("Privacy"),), enabled);
+        Ok(String::from_str(&env, "Privacy mode updated"))
+    }
+
+    fn is_privacy_mode(env: &Env) -> bool {
+        env.storage()
+            .instance()
+            .get(&DataKey::PrivacyMode)
+            .unwrap_or(false)
+    }
+
+    /// Private invoices must leave every plaintext PII field empty, including the vendor
+    /// contact hashes derived from it, so they are never indexed. Public ones must fill them
+    /// all, and are refused while privacy mode is on.
+    fn check_pii(env: &Env, invoice: &Invoice) -> Result<(), InvoiceError> {
+        let plaintext = [
+            &invoice.vendor_email_hash,
+            &invoice.vendor_mobile_hash,
+            &invoice.vendor_email,
+            &invoice.vendor_mobile,
+            &invoice.client_fname,
+            &invoice.client_lname,
+            &invoice.client_email,
+            &invoice.client_mobile,
+        ];
+        match &invoice.privacy {
+            Privacy::Private(pii) => {
+                if pii.blob_ref.is_empty() {
+                    log!(
+                        env,
+                        "Error: Private invoices need an encrypted blob reference"
+                    );
+                    return Err(InvoiceError::InvalidInput);
+                }
+                if plaintext.iter().any(|value| !value.is_empty()) {
+                    log!(env, "Error: Private invoices cannot carry plaintext PII");
+                    return Err(InvoiceError::PlaintextPiiNotAllowed);
+                }
+            }
+            Privacy::Public => {
+                if Self::is_privacy_mode(env) {
+                    log!(env, "Error: Privacy mode requires PII commitments");
+                    return Err(InvoiceError::PlaintextPiiNotAllowed);
+                }
+                if plaintext.iter().any(|value| value.is_empty()) {
+                    log!(env, "Error: One or more input fields are empty");
+                    return Err(InvoiceError::InvalidInput);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `value` salted with `salt` matches the commitment stored for `field` on a
+    /// private invoice
+    pub fn verify_pii(
+        env: Env,
+        mongo_id: String,
+        field: PiiField,
+        value: String,
+        salt: Bytes,
+    ) -> Result<bool, InvoiceError> {
+        let invoice = Self::get_invoice(&env, &mongo_id).ok_or(InvoiceError::NotFound)?;
+        let pii = match invoice.privacy {
+            Privacy::Private(pii) => pii,
+            Privacy::Public => {
+                log!(
+                    &env,
+                    "Error: Invoice {} does not use PII commitments",
+                    mongo_id
+                );
+                return Err(InvoiceError::InvalidInput);
+            }
+        };
+        let commitment = match field {
+            PiiField::VendorEmail => pii.vendor_email,
+            PiiField::VendorMobile => pii.vendor_mobile,
+            PiiField::ClientFname => pii.client_fname,
+            PiiField::ClientLname => pii.client_lname,
+            PiiField::ClientEmail => pii.client_email,
+            PiiField::ClientMobile => pii.client_mobile,
+        };
+
+        let len = value.len() as usize;
+        if len > MAX_PII_LEN {
+            return Ok(false);
+        }
+        let mut buf = [0u8; MAX_PII_LEN];
+        value.copy_into_slice(&mut buf[..len]);
+        let mut preimage = salt;
+        preimage.extend_from_slice(&buf[..len]);
+
+        let digest: BytesN<32> = env.crypto().sha256(&preimage).into();
+        Ok(digest == commitment)
+    }
+
+    /// Register a currency code and the number of decimals its minor unit uses
+    pub fn set_currency(env: Env, currency: String, decimals: u32) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if currency.is_empty() || decimals > MAX_DECIMALS {
+            log!(
+                &env,
+                "Error: Invalid currency {} with {} decimals",
+                currency,
+                decimals
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::CurrencyDecimals(currency.clone()), &decimals);
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(
+            &env,
+            "Currency {} registered with {} decimals",
+            currency,
+            decimals
+        );
+        env.events()
+            .publish((symbol_short!("Currency"), currency), decimals);
+        Ok(String::from_str(&env, "Currency registered"))
+    }
+
+    /// Map a registered currency to the Stellar Asset Contract token used to settle it
+    pub fn set_currency_token(
+        env: Env,
+        currency: String,
+        token: Address,
+    ) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        let decimals = match Self::get_currency_decimals(&env, &currency) {
+            Some(decimals) => decimals,
+            None => {
+                log!(&env, "Error: Currency {} is not registered", currency);
+                return Err(InvoiceError::UnsupportedCurrency);
+            }
+        };
+        let token_decimals = token::Client::new(&env, &token).decimals();
+        if token_decimals != decimals {
+            log!(
+                &env,
+                "Error: Token has {} decimals but currency {} uses {}",
+                token_decimals,
+                currency,
+                decimals
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::CurrencyToken(currency.clone()), &token);
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Currency {} settles in token {}", currency, token);
+        env.events()
+            .publish((symbol_short!("CurToken"), currency), token);
+        Ok(String::from_str(&env, "Currency token registered"))
+    }
+
+    fn get_currency_token(env: &Env, currency: &String) -> Option<Address> {
+        env.storage()
+            .instance()
+            .get(&DataKey::CurrencyToken(currency.clone()))
+    }
+
+    fn get_currency_decimals(env: &Env, currency: &String) -> Option<u32> {
+        env.storage()
+            .instance()
+            .get(&DataKey::CurrencyDecimals(currency.clone()))
+    }
+
+    /// Reject non-positive amounts and amounts outside the supported range
+    fn check_amount(env: &Env, amount: i128) -> Result<(), InvoiceError> {
+        if amount <= 0 || amount > MAX_AMOUNT {
+            log!(env, "Error: Invalid amount {}", amount);
+            return Err(InvoiceError::InvalidAmount);
+        }
+        Ok(())
+    }
+
+    /// Recompute `(subtotal, tax_total)` from the lines, checking each line's declared tax.
+    /// Tax is rounded half up to the nearest minor unit.
+    fn compute_totals(env: &Env, lines: &Vec<LineItem>) -> Result<(i128, i128), InvoiceError> {
+        let mut subtotal: i128 = 0;
+        let mut tax_total: i128 = 0;
+
+        for line in lines.iter() {
+            if line.description.is_empty()
+                || line.quantity == 0
+                || line.unit_price < 0
+                || line.discount < 0
+                || line.tax_rate as i128 > BPS_DENOMINATOR
+            {
+                log!(env, "Error: Invalid line {}", line.description);
+                return Err(InvoiceError::InvalidInput);
+            }
+            let gross = line
+                .unit_price
+                .checked_mul(line.quantity as i128)
+                .ok_or(InvoiceError::InvalidAmount)?;
+            if line.discount > gross {
+                log!(
+                    env,
+                    "Error: Discount exceeds amount on line {}",
+                    line.description
+                );
+                return Err(InvoiceError::InvalidAmount);
+            }
+            let net = gross - line.discount;
+            let tax = net
+                .checked_mul(line.tax_rate as i128)
+                .and_then(|t| t.checked_add(BPS_DENOMINATOR / 2))
+                .ok_or(InvoiceError::InvalidAmount)?
+                / BPS_DENOMINATOR;
+            if tax != line.tax_amount {
+                log!(
+                    env,
+                    "Error: Line {} declares tax {} but {} was computed",
+                    line.description,
+                    line.tax_amount,
+                    tax
+                );
+                return Err(InvoiceError::TotalsMismatch);
+            }
+            subtotal = subtotal
+                .checked_add(net)
+                .ok_or(InvoiceError::InvalidAmount)?;
+            tax_total = tax_total
+                .checked_add(tax)
+                .ok_or(InvoiceError::InvalidAmount)?;
+        }
+        Ok((subtotal, tax_total))
+    }
+
+    /// Split `amount` paid on an invoice between its financier, up to what is still owed on
+    /// the advance and fee, and the vendor's `fund_reception`. Counts the financier's share as
+    /// repaid and returns each recipient with its non-zero share.
+    fn payees(env: &Env, invoice: &mut Invoice, amount: i128) -> Vec<(Address, i128)> {
+        let mut shares = Vec::new(env);
+        let mut rest = amount;
+        if let Some(index) = invoice.financing_details.len().checked_sub(1) {
+            let mut record = invoice.financing_details.get(index).unwrap();
+            let owed = record.advance + record.fee - record.repaid;
+            let share = rest.min(owed);
+            if share > 0 {
+                let advance_repaid = record.repaid.min(record.advance);
+                record.repaid += share;
+                let delta = record.repaid.min(record.advance) - advance_repaid;
+                Self::update_exposure(env, &record.financier, &invoice.vendor, -delta);
+                shares.push_back((record.financier.clone(), share));
+                invoice.financing_details.set(index, record);
+                rest -= share;
+            }
+        }
+        if rest > 0 {
+            shares.push_back((invoice.fund_reception.clone(), rest));
+        }
+        shares
+    }
+
+    /// Settlement token for financing an invoice, checking the financier is a third party
+    fn financing_token(
+        env: &Env,
+        invoice: &Invoice,
+        financier: &Address,
+    ) -> Result<Address, InvoiceError> {
+        if *financier == invoice.vendor || *financier == invoice.client {
+            log!(
+                env,
+                "Error: Invoice parties cannot finance their own invoice"
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+        match Self::get_currency_token(env, &invoice.currency) {
+            Some(token) => Ok(token),
+            None => {
+                log!(
+                    env,
+                    "Error: Currency {} has no settlement token",
+                    invoice.currency
+                );
+                Err(InvoiceError::UnsupportedCurrency)
+            }
+        }
+    }
+
+    /// Check the terms of a financing and that the financier may take it on
+    fn check_financing_terms(
+        env: &Env,
+        invoice: &Invoice,
+        financier: &Address,
+        advance: i128,
+        fee: i128,
+    ) -> Result<(), InvoiceError> {
+        if !invoice.financing_details.is_empty() {
+            log!(
+                env,
+                "Error: Invoice {} is already financed",
+                invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::AlreadyFinanced);
+        }
+        Self::check_amount(env, advance)?;
+        let outstanding = Self::outstanding(env, invoice);
+        if fee < 0
+            || advance
+                .checked_add(fee)
+                .is_none_or(|total| total > outstanding)
+        {
+            log!(
+                env,
+                "Error: Advance {} plus fee {} exceed outstanding balance {}",
+                advance,
+                fee,
+                outstanding
+            );
+            return Err(InvoiceError::InvalidAmount);
+        }
+        Self::check_financier(env, financier, &invoice.vendor, advance)
+    }
+
+    /// Refuse financiers who are unknown, suspended, or would go over either limit by
+    /// advancing `advance` to `vendor`
+    fn check_financier(
+        env: &Env,
+        financier: &Address,
+        vendor: &Address,
+        advance: i128,
+    ) -> Result<(), InvoiceError> {
+        let record = match Self::get_financier(env, financier) {
+            Some(record) => record,
+            None => {
+                log!(
+                    env,
+                    "Error: Financier {} is not registered",
+                    financier.clone()
+                );
+                return Err(InvoiceError::FinancierNotFound);
+            }
+        };
+        if !record.active {
+            log!(env, "Error: Financier {} is suspended", financier.clone());
+            return Err(InvoiceError::FinancierSuspended);
+        }
+        let vendor_exposure = Self::get_vendor_exposure(env, financier, vendor);
+        if record.exposure + advance > record.max_exposure
+            || vendor_exposure + advance > record.vendor_limit
+        {
+            log!(
+                env,
+                "Error: Advance {} exceeds limits of financier {}",
+                advance,
+                financier.clone()
+            );
+            return Err(InvoiceError::ExposureLimitExceeded);
+        }
+        Ok(())
+    }
+
+    fn get_financier(env: &Env, financier: &Address) -> Option<Financier> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Financier(financier.clone()))
+    }
+
+    fn save_financier(env: &Env, financier: &Financier) {
+        let key = DataKey::Financier(financier.address.clone());
+        env.storage().persistent().set(&key, financier);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    fn get_vendor_exposure(env: &Env, financier: &Address, vendor: &Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::VendorExposure(financier.clone(), vendor.clone()))
+            .unwrap_or(0)
+    }
+
+    /// Add `delta` (negative on repayment) to a financier's total and per-vendor exposure
+    fn update_exposure(env: &Env, financier: &Address, vendor: &Address, delta: i128) {
+        if let Some(mut record) = Self::get_financier(env, financier) {
+            record.exposure += delta;
+            Self::save_financier(env, &record);
+        }
+        let key = DataKey::VendorExposure(financier.clone(), vendor.clone());
+        let exposure = Self::get_vendor_exposure(env, financier, vendor) + delta;
+        env.storage().persistent().set(&key, &exposure);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    /// Write off the unrepaid advance of a financed invoice that closed without being paid in
+    /// full, so it no longer counts against the financier's exposure limits
+    fn write_off_financing(env: &Env, invoice: &mut Invoice) {
+        let index = match invoice.financing_details.len() {
+            0 => return,
+            len => len - 1,
+        };
+        let mut record = invoice.financing_details.get(index).unwrap();
+        let remaining = record.advance - record.repaid.min(record.advance) - record.written_off;
+        if remaining <= 0 {
+            return;
+        }
+        record.written_off += remaining;
+        Self::update_exposure(env, &record.financier, &invoice.vendor, -remaining);
+        invoice.financing_details.set(index, record);
+    }
+
+    /// Register a financier or update an existing one's details and limits. The running
+    /// exposure of an existing financier is kept.
+    pub fn register_financier(
+        env: Env,
+        address: Address,
+        name: String,
+        kyc_hash: BytesN<32>,
+        max_exposure: i128,
+        vendor_limit: i128,
+    ) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if name.is_empty() || max_exposure < 0 || vendor_limit < 0 {
+            log!(&env, "Error: Invalid financier details");
+            return Err(InvoiceError::InvalidInput);
+        }
+        let exposure = Self::get_financier(&env, &address).map_or(0, |f| f.exposure);
+        Self::save_financier(
+            &env,
+            &Financier {
+                address: address.clone(),
+                name,
+                kyc_hash,
+                active: true,
+                max_exposure,
+                vendor_limit,
+                exposure,
             },
+        );
+
+        log!(&env, "Financier {} registered", address.clone());
+        env.events().publish((symbol_short!("FinReg"),), address);
+        Ok(String::from_str(&env, "Financier registered"))
+    }
+
+    /// Suspend or reactivate a registered financier
+    pub fn set_financier_active(
+        env: Env,
+        address: Address,
+        active: bool,
+    ) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        let mut financier = match Self::get_financier(&env, &address) {
+            Some(financier) => financier,
+            None => {
+                log!(
+                    &env,
+                    "Error: Financier {} is not registered",
+                    address.clone()
+                );
+                return Err(InvoiceError::FinancierNotFound);
+            }
+        };
+        financier.active = active;
+        Self::save_financier(&env, &financier);
+
+        log!(
+            &env,
+            "Financier {} active set to {}",
+            address.clone(),
+            active
+        );
+        env.events()
+            .publish((symbol_short!("FinActive"), address), active);
+        Ok(String::from_str(&env, "Financier status updated"))
+    }
+
+    /// Move an invoice to `Financed` and record who financed it
+    fn apply_financing(
+        env: &Env,
+        invoice: &mut Invoice,
+        record: FinancingRecord,
+    ) -> Result<(), InvoiceError> {
+        Self::transition(env, invoice, InvoiceStatus::Financed)?;
+        Self::update_exposure(env, &record.financier, &invoice.vendor, record.advance);
+        invoice.financing_details.push_back(record);
+        env.storage()
+            .persistent()
+            .remove(&DataKey::Listing(invoice.mongo_id.clone()));
+        Ok(())
+    }
+
+    fn is_listed(env: &Env, mongo_id: &String) -> bool {
+        env.storage()
+            .persistent()
+            .has(&DataKey::Listing(mongo_id.clone()))
+    }
+
+    fn get_bids(env: &Env, mongo_id: &String) -> Vec<Bid> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Bids(mongo_id.clone()))
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn save_bids(env: &Env, mongo_id: &String, bids: &Vec<Bid>) {
+        let key = DataKey::Bids(mongo_id.clone());
+        env.storage().persistent().set(&key, bids);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    /// Invoice amount after credit notes, debit notes and dispute settlements
+    fn total(invoice: &Invoice) -> i128 {
+        invoice.net_amt + invoice.adjustment
+    }
+
+    /// Whether an invoice still expects payment
+    fn is_open(invoice: &Invoice) -> bool {
+        matches!(
+            invoice.status,
+            InvoiceStatus::Issued
+                | InvoiceStatus::Acknowledged
+                | InvoiceStatus::Financed
+                | InvoiceStatus::PartiallyPaid
+        )
+    }
+
+    fn is_overdue(invoice: &Invoice, as_of: u64) -> bool {
+        Self::is_open(invoice) && invoice.due_date < as_of
+    }
+
+    fn get_late_fee_terms(env: &Env, mongo_id: &String) -> Option<LateFeeTerms> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::LateFee(mongo_id.clone()))
+    }
+
+    /// Late fees owed on an invoice at `as_of`. Accrual stops once the invoice is settled,
+    /// and never drops below what was already recorded.
+    fn late_fee_at(env: &Env, invoice: &Invoice, as_of: u64) -> i128 {
+        if !Self::is_overdue(invoice, as_of) {
+            return invoice.late_fee;
+        }
+        let Some(terms) = Self::get_late_fee_terms(env, &invoice.mongo_id) else {
+            return invoice.late_fee;
+        };
+        let per_period = match terms.kind {
+            LateFeeKind::Flat => terms.amount,
+            LateFeeKind::Percentage => Self::total(invoice) * terms.amount / BPS_DENOMINATOR,
+        };
+        let periods = ((as_of - invoice.due_date) / terms.period) as i128;
+        let accrued = per_period.saturating_mul(periods).min(MAX_AMOUNT);
+        accrued.max(invoice.late_fee)
+    }
+
+    fn get_payment_terms(env: &Env, mongo_id: &String) -> Option<PaymentTerms> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::PaymentTerms(mongo_id.clone()))
+    }
+
+    /// Early-payment discount available on an invoice settled at `as_of`, or the one already
+    /// granted
+    fn discount_at(env: &Env, invoice: &Invoice, as_of: u64) -> i128 {
+        if invoice.discount != 0 || !Self::is_open(invoice) {
+            return invoice.discount;
+        }
+        match Self::get_payment_terms(env, &invoice.mongo_id) {
+            Some(terms) if as_of <= invoice.creation_date + terms.discount_period => {
+                Self::total(invoice) * terms.discount_rate as i128 / BPS_DENOMINATOR
+            }
+            _ => 0,
+        }
+    }
+
+    fn outstanding_at(env: &Env, invoice: &Invoice, as_of: u64) -> i128 {
+        Self::total(invoice) + Self::late_fee_at(env, invoice, as_of)
+            - Self::discount_at(env, invoice, as_of)
+            - invoice.amount_paid
+    }
+
+    /// Balance still owed at the current ledger timestamp, net of any early-payment discount
+    /// and including late fees accrued up to now
+    fn outstanding(env: &Env, invoice: &Invoice) -> i128 {
+        Self::outstanding_at(env, invoice, env.ledger().timestamp())
+    }
+
+    /// Record a payment against the outstanding balance, allocate it to the installment
+    /// schedule and move the invoice to `PartiallyPaid` or `Paid`
+    fn record_payment(
+        env: &Env,
+        invoice: &mut Invoice,
+        payer: &Address,
+        amount: i128,
+        reference: String,
+    ) -> Result<(), InvoiceError> {
+        Self::check_amount(env, amount)?;
+        if invoice.status == InvoiceStatus::Disputed {
+            log!(
+                env,
+                "Error: Invoice {} cannot be paid while disputed",
+                invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        invoice.late_fee = Self::late_fee_at(env, invoice, env.ledger().timestamp());
+        let outstanding = Self::outstanding(env, invoice);
+        if amount > outstanding {
+            log!(
+                env,
+                "Error: Payment of {} exceeds outstanding balance {}",
+                amount,
+                outstanding
+            );
+            return Err(InvoiceError::InvalidAmount);
+        }
+
+        let status = if amount == outstanding {
+            InvoiceStatus::Paid
+        } else {
+            InvoiceStatus::PartiallyPaid
+        };
+        if status == InvoiceStatus::Paid {
+            invoice.discount = Self::discount_at(env, invoice, env.ledger().timestamp());
+        }
+        Self::transition(env, invoice, status)?;
+        invoice.amount_paid += amount;
+
+        let mut payments = Self::get_payments(env, &invoice.mongo_id);
+        payments.push_back(Payment {
+            amount,
+            payer: payer.clone(),
+            timestamp: env.ledger().timestamp(),
+            reference,
+        });
+        let key = DataKey::Payments(invoice.mongo_id.clone());
+        env.storage().persistent().set(&key, &payments);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+
+        let mut schedule = Self::get_installments(env, &invoice.mongo_id);
+        if !schedule.is_empty() {
+            let mut unallocated = amount;
+            for i in 0..schedule.len() {
+                let mut installment = schedule.get(i).unwrap();
+                let due = installment.amount - installment.paid;
+                if due == 0 {
+                    continue;
+                }
+                let allocated = if unallocated < due { unallocated } else { due };
+                installment.paid += allocated;
+                unallocated -= allocated;
+                schedule.set(i, installment);
+                if unallocated == 0 {
+                    break;
+                }
+            }
+            Self::save_installments(env, &invoice.mongo_id, &schedule);
+        }
+        Ok(())
+    }
+
+    /// Set how long escrowed funds stay locked before the client can claim a refund
+    pub fn set_escrow_timeout(env: Env, timeout: u64) -> Result<String, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        if timeout == 0 {
+            log!(&env, "Error: Escrow timeout must be positive");
+            return Err(InvoiceError::InvalidInput);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::EscrowTimeout, &timeout);
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Escrow timeout set to {} seconds", timeout);
+        env.events().publish((symbol_short!("EscTmout"),), timeout);
+        Ok(String::from_str(&env, "Escrow timeout set"))
+    }
+
+    fn get_escrow_timeout(env: &Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&DataKey::EscrowTimeout)
+            .unwrap_or(DEFAULT_ESCROW_TIMEOUT)
+    }
+
+    fn get_escrow(env: &Env, mongo_id: &String) -> Option<Escrow> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Escrow(mongo_id.clone()))
+    }
+
+    fn save_escrow(env: &Env, mongo_id: &String, escrow: &Escrow) {
+        let key = DataKey::Escrow(mongo_id.clone());
+        env.storage().persistent().set(&key, escrow);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    fn escrow_token(env: &Env, invoice: &Invoice) -> Result<Address, InvoiceError> {
+        Self::get_currency_token(env, &invoice.currency).ok_or(InvoiceError::UnsupportedCurrency)
+    }
+
+    /// Pay the whole escrow balance out to the invoice's payee
+    fn release_escrow(
+        env: &Env,
+        invoice: &mut Invoice,
+    ) -> Result<Vec<InvoiceChange>, InvoiceError> {
+        let mut moves = Vec::new(env);
+        let escrow = match Self::get_escrow(env, &invoice.mongo_id) {
+            Some(escrow) => escrow,
+            None => return Ok(moves),
         };
+        let token = token::Client::new(env, &Self::escrow_token(env, invoice)?);
+        for (payee, share) in Self::payees(env, invoice, escrow.balance).iter() {
+            token.transfer(&env.current_contract_address(), &payee, &share);
+            env.events().publish(
+                (symbol_short!("EscrowOut"), invoice.mongo_id.clone()),
+                (payee.clone(), share),
+            );
+            moves.push_back(InvoiceChange::EscrowReleased(payee, share));
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::Escrow(invoice.mongo_id.clone()));
+        Ok(moves)
+    }
+
+    /// Return `amount` of the escrow balance to the client and take it off `amount_paid`
+    fn return_escrow(
+        env: &Env,
+        invoice: &mut Invoice,
+        amount: i128,
+    ) -> Result<InvoiceChange, InvoiceError> {
+        let key = DataKey::Escrow(invoice.mongo_id.clone());
+        let mut escrow = Self::get_escrow(env, &invoice.mongo_id).ok_or(InvoiceError::NotFound)?;
+        let token = Self::escrow_token(env, invoice)?;
+        token::Client::new(env, &token).transfer(
+            &env.current_contract_address(),
+            &invoice.client,
+            &amount,
+        );
+        escrow.balance -= amount;
+        if escrow.balance == 0 {
+            env.storage().persistent().remove(&key);
+        } else {
+            Self::set_persistent(env, &key, &escrow);
+        }
+        invoice.amount_paid -= amount;
+
+        env.events().publish(
+            (symbol_short!("EscRefund"), invoice.mongo_id.clone()),
+            (invoice.client.clone(), amount),
+        );
+        Ok(InvoiceChange::EscrowRefunded(
+            invoice.client.clone(),
+            amount,
+        ))
+    }
+
+    /// Return every escrowed payment to its payer and move the invoice to `Refunded`
+    fn refund_escrowed(
+        env: &Env,
+        invoice: &mut Invoice,
+    ) -> Result<Vec<InvoiceChange>, InvoiceError> {
+        Self::transition(env, invoice, InvoiceStatus::Refunded)?;
+
+        let mut moves = Vec::new(env);
+        if Self::get_escrow(env, &invoice.mongo_id).is_none() {
+            return Ok(moves);
+        }
+        let token = token::Client::new(env, &Self::escrow_token(env, invoice)?);
+        for payment in Self::get_payments(env, &invoice.mongo_id).iter() {
+            token.transfer(
+                &env.current_contract_address(),
+                &payment.payer,
+                &payment.amount,
+            );
+            env.events().publish(
+                (symbol_short!("EscRefund"), invoice.mongo_id.clone()),
+                (payment.payer.clone(), payment.amount),
+            );
+            moves.push_back(InvoiceChange::EscrowRefunded(payment.payer, payment.amount));
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::Escrow(invoice.mongo_id.clone()));
+        invoice.amount_paid = 0;
+        Ok(moves)
+    }
+
+    fn get_payments(env: &Env, mongo_id: &String) -> Vec<Payment> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Payments(mongo_id.clone()))
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn get_installments(env: &Env, mongo_id: &String) -> Vec<Installment> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Installments(mongo_id.clone()))
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn save_installments(env: &Env, mongo_id: &String, schedule: &Vec<Installment>) {
+        let key = DataKey::Installments(mongo_id.clone());
+        env.storage().persistent().set(&key, schedule);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    /// Drop an invoice's installment schedule once its total no longer adds up to it
+    fn clear_installments(env: &Env, mongo_id: &String) {
+        let key = DataKey::Installments(mongo_id.clone());
+        if env.storage().persistent().has(&key) {
+            env.storage().persistent().remove(&key);
+            log!(
+                env,
+                "Invoice {} installment schedule cleared",
+                mongo_id.clone()
+            );
+        }
+    }
+
+    fn get_invoice(env: &Env, mongo_id: &String) -> Option<Invoice> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Invoice(mongo_id.clone()))
+    }
+
+    fn has_invoice(env: &Env, mongo_id: &String) -> bool {
+        env.storage()
+            .persistent()
+            .has(&DataKey::Invoice(mongo_id.clone()))
+    }
+
+    /// Save an invoice under its own persistent entry and extend its TTL
+    fn save_invoice(env: &Env, invoice: &Invoice) {
+        let key = DataKey::Invoice(invoice.mongo_id.clone());
+        env.storage().persistent().set(&key, invoice);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    fn get_invoice_count(env: &Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&DataKey::InvoiceCount)
+            .unwrap_or(0)
+    }
+
+    /// Save a new invoice and register its mongo_id in the enumeration and secondary indexes
+    fn add_invoice(env: &Env, invoice: &Invoice) {
+        Self::register_invoice_id(env, &invoice.mongo_id);
+        Self::update_indexes(env, None, invoice);
+        Self::save_invoice(env, invoice);
+    }
+
+    fn register_invoice_id(env: &Env, mongo_id: &String) {
+        let count = Self::get_invoice_count(env);
+        let key = DataKey::InvoiceId(count);
+        env.storage().persistent().set(&key, mongo_id);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+        env.storage()
+            .instance()
+            .set(&DataKey::InvoiceCount, &(count + 1));
+        env.storage().instance().extend_ttl(1000, 5000);
+    }
+
+    fn get_invoice_id(env: &Env, index: u32) -> Option<String> {
+        env.storage().persistent().get(&DataKey::InvoiceId(index))
+    }
+
+    fn set_persistent(env: &Env, key: &DataKey, value: &impl IntoVal<Env, Val>) {
+        env.storage().persistent().set(key, value);
+        env.storage()
+            .persistent()
+            .extend_ttl(key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    /// Every secondary index an invoice belongs under
+    fn index_keys(env: &Env, invoice: &Invoice) -> Vec<IndexKey> {
+        let mut keys = Vec::new(env);
+        keys.push_back(IndexKey::Vendor(invoice.vendor.clone()));
+        keys.push_back(IndexKey::Client(invoice.client.clone()));
+        keys.push_back(IndexKey::Status(invoice.vendor.clone(), invoice.status));
+        if !invoice.vendor_email_hash.is_empty() {
+            keys.push_back(IndexKey::VendorEmailHash(invoice.vendor_email_hash.clone()));
+        }
+        if !invoice.vendor_mobile_hash.is_empty() {
+            keys.push_back(IndexKey::VendorMobileHash(
+                invoice.vendor_mobile_hash.clone(),
+            ));
+        }
+        if !invoice.vendor_id.is_empty() {
+            keys.push_back(IndexKey::VendorId(invoice.vendor_id.clone()));
+        }
+        if !invoice.txn_hash.is_empty() {
+            keys.push_back(IndexKey::TxnHash(invoice.txn_hash.clone()));
+        }
+        if !invoice.previous_invoice_hash.is_empty() {
+            keys.push_back(IndexKey::TxnHash(invoice.previous_invoice_hash.clone()));
+        }
+        keys
+    }
+
+    fn is_removable(index: &IndexKey) -> bool {
+        matches!(index, IndexKey::Status(..))
+    }
+
+    /// Whether an invoice read through `index` still belongs there. Append-only indexes keep
+    /// entries whose key has since changed, and txn hash entries match any earlier hash.
+    fn in_index(env: &Env, index: &IndexKey, invoice: &Invoice) -> bool {
+        matches!(index, IndexKey::TxnHash(_)) || Self::index_keys(env, invoice).contains(index)
+    }
+
+    fn get_index_tail(env: &Env, index: &IndexKey) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::IndexTail(index.clone()))
+            .unwrap_or(0)
+    }
+
+    fn get_index_page(env: &Env, index: &IndexKey, page: u32) -> Vec<String> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::IndexPage(index.clone(), page))
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn get_index_len(env: &Env, index: &IndexKey) -> u32 {
+        let tail = Self::get_index_tail(env, index);
+        tail * INDEX_PAGE_SIZE + Self::get_index_page(env, index, tail).len()
+    }
+
+    /// Position of `mongo_id` in a removable `index`, if its recorded position is in range
+    /// and still points back at it
+    fn index_position(env: &Env, index: &IndexKey, mongo_id: &String) -> Option<u32> {
+        let pos: u32 = env
+            .storage()
+            .persistent()
+            .get(&DataKey::IndexPos(index.clone(), mongo_id.clone()))?;
+        if pos >= Self::get_index_len(env, index) {
+            return None;
+        }
+        let page = Self::get_index_page(env, index, pos / INDEX_PAGE_SIZE);
+        match page.get(pos % INDEX_PAGE_SIZE) {
+            Some(entry) if entry == *mongo_id => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// Append `mongo_id` to the last page of `index`. Removable indexes skip ids they
+    /// already hold and record where the id went.
+    fn index_add(env: &Env, index: &IndexKey, mongo_id: &String) -> bool {
+        let removable = Self::is_removable(index);
+        if removable && Self::index_position(env, index, mongo_id).is_some() {
+            return false;
+        }
+        let mut tail = Self::get_index_tail(env, index);
+        let mut page = Self::get_index_page(env, index, tail);
+        if page.len() >= INDEX_PAGE_SIZE {
+            tail += 1;
+            page = Vec::new(env);
+            Self::set_persistent(env, &DataKey::IndexTail(index.clone()), &tail);
+        }
+        if removable {
+            Self::set_persistent(
+                env,
+                &DataKey::IndexPos(index.clone(), mongo_id.clone()),
+                &(tail * INDEX_PAGE_SIZE + page.len()),
+            );
+        }
+        page.push_back(mongo_id.clone());
+        Self::set_persistent(env, &DataKey::IndexPage(index.clone(), tail), &page);
+        true
+    }
+
+    /// Remove `mongo_id` from a removable `index` by moving the last entry into its slot
+    fn index_remove(env: &Env, index: &IndexKey, mongo_id: &String) -> bool {
+        let Some(pos) = Self::index_position(env, index, mongo_id) else {
+            return false;
+        };
+        let tail = Self::get_index_tail(env, index);
+        let mut last_page = Self::get_index_page(env, index, tail);
+        let moved = last_page.pop_back().unwrap();
+        if moved != *mongo_id {
+            let (page_no, slot) = (pos / INDEX_PAGE_SIZE, pos % INDEX_PAGE_SIZE);
+            if page_no == tail {
+                last_page.set(slot, moved.clone());
+            } else {
+                let mut page = Self::get_index_page(env, index, page_no);
+                page.set(slot, moved.clone());
+                Self::set_persistent(env, &DataKey::IndexPage(index.clone(), page_no), &page);
+            }
+            Self::set_persistent(env, &DataKey::IndexPos(index.clone(), moved), &pos);
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::IndexPos(index.clone(), mongo_id.clone()));
+        if last_page.is_empty() {
+            env.storage()
+                .persistent()
+                .remove(&DataKey::IndexPage(index.clone(), tail));
+            if tail > 0 {
+                Self::set_persistent(env, &DataKey::IndexTail(index.clone()), &(tail - 1));
+            }
+        } else {
+            Self::set_persistent(env, &DataKey::IndexPage(index.clone(), tail), &last_page);
+        }
+        true
+    }
+
+    /// Move an invoice between secondary indexes after a write. Only keys that changed
+    /// are touched; an invoice leaves only removable indexes.
+    fn update_indexes(env: &Env, before: Option<&Invoice>, after: &Invoice) {
+        let keys = Self::index_keys(env, after);
+        let old_keys = match before {
+            Some(before) => Self::index_keys(env, before),
+            None => Vec::new(env),
+        };
+        for key in old_keys.iter() {
+            if Self::is_removable(&key) && !keys.contains(&key) {
+                Self::index_remove(env, &key, &after.mongo_id);
+            }
+        }
+        for key in keys.iter() {
+            if !old_keys.contains(&key) {
+                Self::index_add(env, &key, &after.mongo_id);
+            }
+        }
+    }
+
+    fn get_history_count(env: &Env, mongo_id: &String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::HistoryCount(mongo_id.clone()))
+            .unwrap_or(0)
+    }
+
+    fn get_history_entry(env: &Env, mongo_id: &String, index: u32) -> Option<HistoryEntry> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::History(mongo_id.clone(), index))
+    }
+
+    /// Replay a history event on top of the invoice state before it
+    fn apply_event(before: Option<Invoice>, event: &HistoryEvent) -> Option<Invoice> {
+        let mut current = before;
+        for change in event.changes.iter() {
+            if let InvoiceChange::Created(invoice) = change {
+                current = Some(invoice);
+                continue;
+            }
+            let invoice = current.as_mut()?;
+            match change {
+                InvoiceChange::Created(_) => {}
+                InvoiceChange::Status(value) => invoice.status = value,
+                InvoiceChange::Action(value) => invoice.action = value,
+                InvoiceChange::TxnHash(value) => invoice.txn_hash = value,
+                InvoiceChange::PreviousInvoiceHash(value) => invoice.previous_invoice_hash = value,
+                InvoiceChange::Timestamp(value) => invoice.timestamp = value,
+                InvoiceChange::Tracking(value) => invoice.tracking = value,
+                InvoiceChange::AmountPaid(value) => invoice.amount_paid = value,
+                InvoiceChange::Lines(value) => invoice.lines = value,
+                InvoiceChange::Subtotal(value) => invoice.subtotal = value,
+                InvoiceChange::TaxTotal(value) => invoice.tax_total = value,
+                InvoiceChange::NetAmt(value) => invoice.net_amt = value,
+                InvoiceChange::FundReception(value) => invoice.fund_reception = value,
+                InvoiceChange::Adjustment(value) => invoice.adjustment = value,
+                InvoiceChange::LateFee(value) => invoice.late_fee = value,
+                InvoiceChange::Discount(value) => invoice.discount = value,
+                InvoiceChange::DueDate(value) => invoice.due_date = value,
+                InvoiceChange::FinancingDetails(value) => invoice.financing_details = value,
+                InvoiceChange::SentInvoiceDeleted(value) => invoice.sent_invoice_deleted = value,
+                InvoiceChange::ReceivedInvoiceDeleted(value) => {
+                    invoice.received_invoice_deleted = value
+                }
+                InvoiceChange::DeletedComments(value) => invoice.deleted_comments = value,
+                InvoiceChange::EscrowReleased(..)
+                | InvoiceChange::EscrowRefunded(..)
+                | InvoiceChange::Dispute(_) => {}
+            }
+        }
+        current
+    }
+
+    /// Every field that differs between two versions of an invoice
+    fn diff(env: &Env, before: &Invoice, after: &Invoice) -> Vec<InvoiceChange> {
+        let mut changes = Vec::new(env);
+        if before.status != after.status {
+            changes.push_back(InvoiceChange::Status(after.status));
+        }
+        if before.action != after.action {
+            changes.push_back(InvoiceChange::Action(after.action.clone()));
+        }
+        if before.txn_hash != after.txn_hash {
+            changes.push_back(InvoiceChange::TxnHash(after.txn_hash.clone()));
+        }
+        if before.previous_invoice_hash != after.previous_invoice_hash {
+            changes.push_back(InvoiceChange::PreviousInvoiceHash(
+                after.previous_invoice_hash.clone(),
+            ));
+        }
+        if before.timestamp != after.timestamp {
+            changes.push_back(InvoiceChange::Timestamp(after.timestamp));
+        }
+        if before.tracking != after.tracking {
+            changes.push_back(InvoiceChange::Tracking(after.tracking.clone()));
+        }
+        if before.amount_paid != after.amount_paid {
+            changes.push_back(InvoiceChange::AmountPaid(after.amount_paid));
+        }
+        if before.lines != after.lines {
+            changes.push_back(InvoiceChange::Lines(after.lines.clone()));
+        }
+        if before.subtotal != after.subtotal {
+            changes.push_back(InvoiceChange::Subtotal(after.subtotal));
+        }
+        if before.tax_total != after.tax_total {
+            changes.push_back(InvoiceChange::TaxTotal(after.tax_total));
+        }
+        if before.net_amt != after.net_amt {
+            changes.push_back(InvoiceChange::NetAmt(after.net_amt));
+        }
+        if before.fund_reception != after.fund_reception {
+            changes.push_back(InvoiceChange::FundReception(after.fund_reception.clone()));
+        }
+        if before.adjustment != after.adjustment {
+            changes.push_back(InvoiceChange::Adjustment(after.adjustment));
+        }
+        if before.late_fee != after.late_fee {
+            changes.push_back(InvoiceChange::LateFee(after.late_fee));
+        }
+        if before.discount != after.discount {
+            changes.push_back(InvoiceChange::Discount(after.discount));
+        }
+        if before.due_date != after.due_date {
+            changes.push_back(InvoiceChange::DueDate(after.due_date));
+        }
+        if before.financing_details != after.financing_details {
+            changes.push_back(InvoiceChange::FinancingDetails(
+                after.financing_details.clone(),
+            ));
+        }
+        if before.sent_invoice_deleted != after.sent_invoice_deleted {
+            changes.push_back(InvoiceChange::SentInvoiceDeleted(
+                after.sent_invoice_deleted,
+            ));
+        }
+        if before.received_invoice_deleted != after.received_invoice_deleted {
+            changes.push_back(InvoiceChange::ReceivedInvoiceDeleted(
+                after.received_invoice_deleted,
+            ));
+        }
+        if before.deleted_comments != after.deleted_comments {
+            changes.push_back(InvoiceChange::DeletedComments(
+                after.deleted_comments.clone(),
+            ));
+        }
+        changes
+    }
+
+    /// Invoice state after history event `index`, replayed from the latest stored snapshot
+    /// at or before it
+    fn history_state(env: &Env, mongo_id: &String, index: u32) -> Option<Invoice> {
+        let start = (index + 1) / HISTORY_SNAPSHOT_INTERVAL * HISTORY_SNAPSHOT_INTERVAL;
+        let mut current = match start {
+            0 => None,
+            _ => env
+                .storage()
+                .persistent()
+                .get(&DataKey::HistorySnapshot(mongo_id.clone(), start - 1)),
+        };
+        for i in start..=index {
+            let entry = Self::get_history_entry(env, mongo_id, i)?;
+            current = Self::apply_event(current, &entry.event);
+        }
+        current
+    }
+
+    /// Full snapshots of an invoice after each of up to `limit` history events from `cursor`
+    fn get_history(env: &Env, mongo_id: &String, cursor: u32, limit: u32) -> Vec<Invoice> {
+        let mut history = Vec::new(env);
+        let end = cursor
+            .saturating_add(limit)
+            .min(Self::get_history_count(env, mongo_id));
+        if cursor >= end {
+            return history;
+        }
+        let mut current = match cursor {
+            0 => None,
+            _ => Self::history_state(env, mongo_id, cursor - 1),
+        };
+        for i in cursor..end {
+            if let Some(entry) = Self::get_history_entry(env, mongo_id, i) {
+                current = Self::apply_event(current, &entry.event);
+                if let Some(invoice) = current.clone() {
+                    history.push_back(invoice);
+                }
+            }
+        }
+        history
+    }
+
+    fn history_digest(env: &Env, event: &HistoryEvent, prev_digest: &BytesN<32>) -> BytesN<32> {
+        let mut preimage = event.clone().to_xdr(env);
+        preimage.append(&Bytes::from(prev_digest));
+        env.crypto().sha256(&preimage).into()
+    }
+
+    /// Save an invoice, update its secondary indexes and append what changed since its
+    /// stored version to its history. An invoice with no stored version is recorded as created.
+    fn commit(env: &Env, invoice: &Invoice, action: Symbol, actor: Option<Address>) {
+        Self::commit_with(env, invoice, action, actor, Vec::new(env));
+    }
+
+    /// `commit`, recording `extra` changes such as fund movements after the field changes
+    fn commit_with(
+        env: &Env,
+        invoice: &Invoice,
+        action: Symbol,
+        actor: Option<Address>,
+        extra: Vec<InvoiceChange>,
+    ) {
+        let before = Self::get_invoice(env, &invoice.mongo_id);
+        let mut changes = match &before {
+            Some(before) => Self::diff(env, before, invoice),
+            None => Vec::from_array(env, [InvoiceChange::Created(invoice.clone())]),
+        };
+        changes.append(&extra);
+        Self::update_indexes(env, before.as_ref(), invoice);
+        Self::save_history(
+            env,
+            &invoice.mongo_id,
+            HistoryEvent {
+                action,
+                actor,
+                changes,
+                timestamp: env.ledger().timestamp(),
+                txn_hash: invoice.txn_hash.clone(),
+            },
+            invoice,
+        );
+        Self::save_invoice(env, invoice);
+    }
+
+    /// Append a history event, chained to the previous one, under its own persistent entry
+    /// and extend its TTL. Every `HISTORY_SNAPSHOT_INTERVAL`th event also stores `after`, the
+    /// invoice as the event left it.
+    fn save_history(env: &Env, mongo_id: &String, event: HistoryEvent, after: &Invoice) {
+        let count = Self::get_history_count(env, mongo_id);
+
+        let prev_digest = match count {
+            0 => BytesN::from_array(env, &[0; 32]),
+            _ => {
+                Self::get_history_entry(env, mongo_id, count - 1)
+                    .unwrap()
+                    .digest
+            }
+        };
+        let entry = HistoryEntry {
+            digest: Self::history_digest(env, &event, &prev_digest),
+            event,
+            prev_digest,
+        };
+
+        let entry_key = DataKey::History(mongo_id.clone(), count);
+        env.storage().persistent().set(&entry_key, &entry);
+        env.storage().persistent().extend_ttl(
+            &entry_key,
+            INVOICE_TTL_THRESHOLD,
+            INVOICE_TTL_EXTEND,
+        );
+
+        if (count + 1).is_multiple_of(HISTORY_SNAPSHOT_INTERVAL) {
+            Self::set_persistent(
+                env,
+                &DataKey::HistorySnapshot(mongo_id.clone(), count),
+                after,
+            );
+        }
+
+        let count_key = DataKey::HistoryCount(mongo_id.clone());
+        env.storage().persistent().set(&count_key, &(count + 1));
+        env.storage().persistent().extend_ttl(
+            &count_key,
+            INVOICE_TTL_THRESHOLD,
+            INVOICE_TTL_EXTEND,
+        );
+    }
+
+    /// Parse a legacy decimal string such as "1700000000" or "99.50" into an integer with
+    /// `decimals` fraction digits. More fraction digits than that are rejected, not rounded.
+    fn parse_decimal(value: &String, decimals: u32) -> Option<i128> {
+        let len = value.len() as usize;
+        if len == 0 || len > MAX_DECIMAL_LEN {
+            return None;
+        }
+        let mut buf = [0u8; MAX_DECIMAL_LEN];
+        value.copy_into_slice(&mut buf[..len]);
+
+        let mut result: i128 = 0;
+        let mut fraction: Option<u32> = None;
+        for &byte in &buf[..len] {
+            match byte {
+                b'0'..=b'9' => {
+                    if let Some(digits) = fraction.as_mut() {
+                        if *digits == decimals {
+                            return None;
+                        }
+                        *digits += 1;
+                    }
+                    result = result.checked_mul(10)?.checked_add((byte - b'0') as i128)?;
+                }
+                b'.' if fraction.is_none() => fraction = Some(0),
+                _ => return None,
+            }
+        }
+        result.checked_mul(10i128.checked_pow(decimals - fraction.unwrap_or(0))?)
+    }
+
+    /// Convert a legacy invoice. Its status flags become a status, its amount is parsed in
+    /// the currency's registered decimals and its free-text lines become a single line
+    /// item. Free-text financing details and fund reception are not carried over; funds go
+    /// to the vendor. Unparseable dates fall back to the legacy timestamp.
+    fn from_legacy(
+        env: &Env,
+        legacy: LegacyInvoice,
+        parties: &LegacyParties,
+    ) -> Result<Invoice, InvoiceError> {
+        let decimals = match Self::get_currency_decimals(env, &legacy.currency) {
+            Some(decimals) => decimals,
+            None => {
+                log!(env, "Error: Currency {} is not registered", legacy.currency);
+                return Err(InvoiceError::UnsupportedCurrency);
+            }
+        };
+        let net_amt = match Self::parse_decimal(&legacy.net_amt, decimals) {
+            Some(amount) if amount <= MAX_AMOUNT => amount,
+            _ => {
+                log!(
+                    env,
+                    "Error: Invoice {} has an invalid amount",
+                    legacy.mongo_id
+                );
+                return Err(InvoiceError::InvalidAmount);
+            }
+        };
+        let date = |value: &String| {
+            Self::parse_decimal(value, 0)
+                .and_then(|seconds| u64::try_from(seconds).ok())
+                .unwrap_or(legacy.timestamp)
+        };
+
+        let status = if legacy.voided {
+            InvoiceStatus::Voided
+        } else if legacy.rejected {
+            InvoiceStatus::Rejected
+        } else if legacy.payment_confirmation {
+            InvoiceStatus::PaymentConfirmed
+        } else if legacy.paid {
+            InvoiceStatus::Paid
+        } else if legacy.finance {
+            InvoiceStatus::Financed
+        } else if legacy.ack {
+            InvoiceStatus::Acknowledged
+        } else {
+            InvoiceStatus::Issued
+        };
+        let settled = matches!(
+            status,
+            InvoiceStatus::Paid | InvoiceStatus::PaymentConfirmed
+        );
+
+        Ok(Invoice {
+            inv_type: legacy.inv_type,
+            vendor: parties.vendor.clone(),
+            client: parties.client.clone(),
+            vendor_id: legacy.vendor_id,
+            mongo_id: legacy.mongo_id,
+            creation_date: date(&legacy.creation_date),
+            vendor_email: legacy.vendor_email,
+            action: legacy.action,
+            status,
+            financing_details: Vec::new(env),
+            vendor_email_hash: legacy.vendor_email_hash,
+            vendor_mobile_hash: legacy.vendor_mobile_hash,
+            vendor_mobile: legacy.vendor_mobile,
+            client_fname: legacy.client_fname,
+            client_lname: legacy.client_lname,
+            vendor_name: legacy.vendor_name,
+            client_email: legacy.client_email,
+            client_mobile: legacy.client_mobile,
+            currency: legacy.currency,
+            fund_reception: parties.vendor.clone(),
+            lines: Vec::from_array(
+                env,
+                [LineItem {
+                    description: legacy.lines,
+                    quantity: 1,
+                    unit_price: net_amt,
+                    discount: 0,
+                    tax_rate: 0,
+                    tax_amount: 0,
+                }],
+            ),
+            subtotal: net_amt,
+            tax_total: 0,
+            net_amt,
+            adjustment: 0,
+            amount_paid: if settled { net_amt } else { 0 },
+            late_fee: 0,
+            discount: 0,
+            escrow: false,
+            decimals,
+            sent_invoice_deleted: legacy.sent_invoice_deleted,
+            received_invoice_deleted: legacy.received_invoice_deleted,
+            timestamp: legacy.timestamp,
+            previous_invoice_hash: legacy.previous_invoice_hash,
+            txn_hash: legacy.txn_hash,
+            due_date: date(&legacy.due_date),
+            deleted_comments: legacy.deleted_comments,
+            tracking: legacy.tracking,
+            privacy: Privacy::Public,
+        })
+    }
+
+    /// Move legacy invoices out of the instance-storage `MAP` into per-invoice persistent
+    /// entries, with their `HISTORY` snapshots. `parties[i]` names the vendor and client of
+    /// the invoice at position `cursor + i` of `MAP` in key order. Returns the cursor for
+    /// the next batch, or `None` once the last batch has been moved and both maps removed.
+    /// Each snapshot costs a write on top of those of creating an invoice, so invoices with
+    /// long histories may need smaller batches than `MAX_MIGRATE_BATCH`.
+    ///
+    /// An instance reaches this code through `upgrade`, so its admin is already set. The
+    /// original contract has neither an admin nor an upgrade entry point, so its deployments
+    /// cannot be upgraded in place; their invoices have to be re-created on a new deployment.
+    pub fn migrate_storage(
+        env: Env,
+        cursor: u32,
+        parties: Vec<LegacyParties>,
+    ) -> Result<Option<u32>, InvoiceError> {
+        Self::require_admin(&env)?;
+
+        let legacy_invoices: Map<String, LegacyInvoice> =
+            match env.storage().instance().get(&INVOICE_MAP) {
+                Some(invoices) => invoices,
+                None => {
+                    log!(&env, "Error: No legacy storage left to migrate");
+                    return Err(InvoiceError::NotFound);
+                }
+            };
+        let legacy_history: Map<String, Vec<LegacyInvoice>> = env
+            .storage()
+            .instance()
+            .get(&INVOICE_HISTORY_MAP)
+            .unwrap_or(Map::new(&env));
+        let keys = legacy_invoices.keys();
+        if keys.is_empty() {
+            env.storage().instance().remove(&INVOICE_MAP);
+            env.storage().instance().remove(&INVOICE_HISTORY_MAP);
+            return Ok(None);
+        }
+        Self::check_batch(&env, parties.len(), MAX_MIGRATE_BATCH)?;
+        let end = cursor.saturating_add(parties.len());
+        if end > keys.len() {
+            log!(
+                &env,
+                "Error: Batch runs past the {} legacy invoices",
+                keys.len()
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+
+        let mut migrated: u32 = 0;
+        for (i, party) in parties.iter().enumerate() {
+            let mongo_id = keys.get_unchecked(cursor + i as u32);
+            if party.mongo_id != mongo_id {
+                log!(
+                    &env,
+                    "Error: Legacy invoice {} was given parties for {}",
+                    mongo_id,
+                    party.mongo_id
+                );
+                return Err(InvoiceError::InvalidInput);
+            }
+            if Self::has_invoice(&env, &mongo_id) {
+                continue;
+            }
+
+            let mut before: Option<Invoice> = None;
+            let history = legacy_history
+                .get(mongo_id.clone())
+                .unwrap_or(Vec::new(&env));
+            for snapshot in history.iter() {
+                let snapshot = Self::from_legacy(&env, snapshot, &party)?;
+                let changes = match &before {
+                    Some(before) => Self::diff(&env, before, &snapshot),
+                    None => Vec::from_array(&env, [InvoiceChange::Created(snapshot.clone())]),
+                };
+                Self::save_history(
+                    &env,
+                    &mongo_id,
+                    HistoryEvent {
+                        action: symbol_short!("migrate"),
+                        actor: None,
+                        changes,
+                        timestamp: snapshot.timestamp,
+                        txn_hash: snapshot.txn_hash.clone(),
+                    },
+                    &snapshot,
+                );
+                before = Some(snapshot);
+            }
+            let invoice = Self::from_legacy(&env, legacy_invoices.get_unchecked(mongo_id), &party)?;
+            Self::add_invoice(&env, &invoice);
+            migrated += 1;
+        }
+
+        let next = if end < keys.len() {
+            Some(end)
+        } else {
+            env.storage().instance().remove(&INVOICE_MAP);
+            env.storage().instance().remove(&INVOICE_HISTORY_MAP);
+            None
+        };
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Migrated {} invoices to persistent storage", migrated);
+        env.events().publish((symbol_short!("Migrated"),), migrated);
+        Ok(next)
+    }
+
+    /// Rebuild `index` from the invoices themselves. The call with `cursor` 0 empties the
+    /// index, and each call re-adds up to `limit` invoices in creation order. Returns the
+    /// cursor for the next call, or `None` once every invoice has been checked. Lookups on
+    /// `index` are incomplete until the rebuild finishes.
+    pub fn rebuild_index(
+        env: Env,
+        index: IndexKey,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<Option<u32>, InvoiceError> {
+        Self::require_admin(&env)?;
+        if limit == 0 {
+            log!(&env, "Error: Page limit must be positive");
+            return Err(InvoiceError::InvalidInput);
+        }
+
+        // Pages past the new last page are never read, and are overwritten as it grows
+        if cursor == 0 {
+            let storage = env.storage().persistent();
+            storage.remove(&DataKey::IndexTail(index.clone()));
+            storage.remove(&DataKey::IndexPage(index.clone(), 0));
+        }
+
+        let count = Self::get_invoice_count(&env);
+        let end = cursor
+            .saturating_add(limit.min(MAX_REINDEX_PAGE))
+            .min(count);
+        let mut added: u32 = 0;
+        for i in cursor..end {
+            let Some(mongo_id) = Self::get_invoice_id(&env, i) else {
+                continue;
+            };
+            let Some(invoice) = Self::get_invoice(&env, &mongo_id) else {
+                continue;
+            };
+            if Self::index_keys(&env, &invoice).contains(&index)
+                && Self::index_add(&env, &index, &mongo_id)
+            {
+                added += 1;
+            }
+        }
+
+        log!(
+            &env,
+            "Reindexed invoices {} to {}, added {}",
+            cursor,
+            end,
+            added
+        );
+        Ok(if end < count { Some(end) } else { None })
+    }
+
+    /// Move an invoice to `to` if the transition table allows it
+    fn transition(env: &Env, invoice: &mut Invoice, to: InvoiceStatus) -> Result<(), InvoiceError> {
+        let from = invoice.status;
+        if !INVOICE_TRANSITIONS.contains(&(from, to)) {
+            log!(
+                env,
+                "Error: Invoice {} cannot move from {} to {}",
+                invoice.mongo_id.clone(),
+                from,
+                to
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        invoice.status = to;
+        if CLOSED_STATUSES.contains(&to) {
+            Self::write_off_financing(env, invoice);
+        }
+        Ok(())
+    }
+
+    /// Validate a new invoice or note and build its initial stored form
+    fn build_invoice(
+        env: &Env,
+        invoice_input: Invoice,
+        inv_type: &str,
+        status: InvoiceStatus,
+    ) -> Result<Invoice, InvoiceError> {
+        if invoice_input.mongo_id.is_empty()
+            || invoice_input.vendor_id.is_empty()
+            || invoice_input.action.is_empty()
+            || invoice_input.vendor_name.is_empty()
+            || invoice_input.currency.is_empty()
+            || invoice_input.lines.is_empty()
+            || invoice_input.txn_hash.is_empty()
+            || invoice_input.creation_date == 0
+        {
+            log!(env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if invoice_input.due_date < invoice_input.creation_date {
+            log!(env, "Error: Due date is before the creation date");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if invoice_input.vendor == invoice_input.client {
+            log!(env, "Error: Vendor and client must be different accounts");
+            return Err(InvoiceError::InvalidInput);
+        }
+        Self::check_pii(env, &invoice_input)?;
+        let decimals = match Self::get_currency_decimals(env, &invoice_input.currency) {
+            Some(decimals) => decimals,
+            None => {
+                log!(
+                    env,
+                    "Error: Currency {} is not registered",
+                    invoice_input.currency
+                );
+                return Err(InvoiceError::UnsupportedCurrency);
+            }
+        };
+        if invoice_input.escrow && Self::get_currency_token(env, &invoice_input.currency).is_none()
+        {
+            log!(
+                env,
+                "Error: Escrow requires a settlement token for {}",
+                invoice_input.currency
+            );
+            return Err(InvoiceError::UnsupportedCurrency);
+        }
+        Self::check_amount(env, invoice_input.net_amt)?;
+        let (subtotal, tax_total) = Self::compute_totals(env, &invoice_input.lines)?;
+        if subtotal != invoice_input.subtotal
+            || tax_total != invoice_input.tax_total
+            || subtotal.checked_add(tax_total) != Some(invoice_input.net_amt)
+        {
+            log!(
+                env,
+                "Error: Declared totals {} + {} = {} do not match lines {} + {}",
+                invoice_input.subtotal,
+                invoice_input.tax_total,
+                invoice_input.net_amt,
+                subtotal,
+                tax_total
+            );
+            return Err(InvoiceError::TotalsMismatch);
+        }
+        if Self::has_invoice(env, &invoice_input.mongo_id) {
+            log!(
+                env,
+                "Error: Invoice with mongo_id {} already exists",
+                invoice_input.mongo_id
+            );
+            return Err(InvoiceError::InvoiceAlreadyExists);
+        }
+
+        Ok(Invoice {
+            mongo_id: invoice_input.mongo_id,
+            inv_type: String::from_str(env, inv_type),
+            vendor: invoice_input.vendor,
+            client: invoice_input.client,
+            vendor_id: invoice_input.vendor_id,
+            creation_date: invoice_input.creation_date,
+            vendor_email: invoice_input.vendor_email,
+            action: invoice_input.action,
+            status,
+            financing_details: Vec::new(env),
+            vendor_email_hash: invoice_input.vendor_email_hash,
+            vendor_mobile_hash: invoice_input.vendor_mobile_hash,
+            vendor_mobile: invoice_input.vendor_mobile,
+            client_fname: invoice_input.client_fname,
+            client_lname: invoice_input.client_lname,
+            vendor_name: invoice_input.vendor_name,
+            client_email: invoice_input.client_email,
+            client_mobile: invoice_input.client_mobile,
+            privacy: invoice_input.privacy,
+            currency: invoice_input.currency,
+            fund_reception: invoice_input.fund_reception,
+            lines: invoice_input.lines,
+            subtotal,
+            tax_total,
+            net_amt: invoice_input.net_amt,
+            amount_paid: 0,
+            adjustment: 0,
+            late_fee: 0,
+            discount: 0,
+            escrow: invoice_input.escrow,
+            decimals,
+            sent_invoice_deleted: false,
+            received_invoice_deleted: false,
+            // timestamp: invoice_input.timestamp,
+            timestamp: env.ledger().timestamp(),
+            previous_invoice_hash: String::from_str(env, ""),
+            txn_hash: invoice_input.txn_hash,
+            due_date: invoice_input.due_date,
+            deleted_comments: String::from_str(env, ""),
+            tracking: Track {
+                subject: String::from_str(env, ""),
+                status: String::from_str(env, ""),
+                msg_id: String::from_str(env, ""),
+                api_key_id: String::from_str(env, ""),
+                event: String::from_str(env, ""),
+                to: String::from_str(env, ""),
+            },
+        })
+    }
+
+    /// Add or update an invoice. Must be authorized by the invoice's vendor.
+    pub fn create_invoice(env: Env, invoice_input: Invoice) -> Result<String, InvoiceError> {
+        invoice_input.vendor.require_auth();
+        Self::create(&env, invoice_input)?;
+        Ok(String::from_str(&env, "Invoice created"))
+    }
+
+    /// Create up to `MAX_CREATE_BATCH` invoices of one vendor, each validated as in
+    /// `create_invoice`. Must be authorized by the vendor of the first invoice; invoices of
+    /// another vendor and invalid ones are skipped and reported in their result.
+    pub fn create_invoices(
+        env: Env,
+        invoices: Vec<Invoice>,
+    ) -> Result<Vec<BatchResult>, InvoiceError> {
+        Self::check_batch(&env, invoices.len(), MAX_CREATE_BATCH)?;
+        let vendor = invoices.get_unchecked(0).vendor;
+        vendor.require_auth();
+
+        let mut results = Vec::new(&env);
+        for invoice_input in invoices.iter() {
+            let mongo_id = invoice_input.mongo_id.clone();
+            let result = if invoice_input.vendor != vendor {
+                log!(
+                    &env,
+                    "Error: Invoice {} is not from the batch vendor",
+                    mongo_id
+                );
+                Err(InvoiceError::Unauthorized)
+            } else {
+                Self::create(&env, invoice_input)
+            };
+            results.push_back(Self::batch_result(mongo_id, result));
+        }
+        Ok(results)
+    }
+
+    fn create(env: &Env, invoice_input: Invoice) -> Result<(), InvoiceError> {
+        Self::check_not_recurring_id(env, &invoice_input.mongo_id)?;
+        let invoice = Self::build_invoice(env, invoice_input, INVOICE_TYPE, InvoiceStatus::Issued)?;
+        Self::commit(
+            env,
+            &invoice,
+            symbol_short!("create"),
+            Some(invoice.vendor.clone()),
+        );
+        Self::register_invoice_id(env, &invoice.mongo_id);
+
+        log!(env, "Invoice with mongo_id {} created", invoice.mongo_id);
+        env.events()
+            .publish((symbol_short!("Created"),), invoice.mongo_id.clone());
+        Ok(())
+    }
+
+    fn check_batch(env: &Env, len: u32, max: u32) -> Result<(), InvoiceError> {
+        if len == 0 || len > max {
+            log!(env, "Error: Batch must hold between 1 and {} items", max);
+            return Err(InvoiceError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Authorize `address` unless it already is in `authorized`; the host rejects a second
+    /// `require_auth` for the same address within one invocation
+    fn require_auth_once(authorized: &mut Vec<Address>, address: &Address) {
+        if !authorized.contains(address) {
+            address.require_auth();
+            authorized.push_back(address.clone());
+        }
+    }
+
+    fn batch_result(mongo_id: String, result: Result<(), InvoiceError>) -> BatchResult {
+        BatchResult {
+            mongo_id,
+            error: result.err().map(|err| err as u32),
+        }
+    }
+
+    /// Create a credit or debit note against `parent_id` and apply it to the parent's
+    /// adjusted amount, dropping the parent's installment schedule
+    fn create_note(
+        env: &Env,
+        parent_id: String,
+        note_input: Invoice,
+        inv_type: &str,
+    ) -> Result<Invoice, InvoiceError> {
+        note_input.vendor.require_auth();
+
+        let mut parent = match Self::get_invoice(env, &parent_id) {
+            Some(parent) => parent,
+            None => {
+                log!(env, "Invoice {} not found", parent_id);
+                return Err(InvoiceError::NotFound);
+            }
+        };
+        if parent.inv_type != String::from_str(env, INVOICE_TYPE)
+            || note_input.vendor != parent.vendor
+            || note_input.client != parent.client
+            || note_input.currency != parent.currency
+        {
+            log!(
+                env,
+                "Error: Note does not match parent invoice {}",
+                parent_id
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+        if !matches!(
+            parent.status,
+            InvoiceStatus::Issued | InvoiceStatus::Acknowledged | InvoiceStatus::PartiallyPaid
+        ) {
+            log!(
+                env,
+                "Error: Invoice {} cannot be adjusted in status {}",
+                parent_id,
+                parent.status
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        Self::check_not_recurring_id(env, &note_input.mongo_id)?;
+        let note = Self::build_invoice(env, note_input, inv_type, InvoiceStatus::Applied)?;
+
+        let action = if inv_type == CREDIT_NOTE_TYPE {
+            Self::check_not_financed(env, &parent)?;
+            let outstanding = Self::outstanding(env, &parent);
+            if note.net_amt > outstanding {
+                log!(
+                    env,
+                    "Error: Credit of {} exceeds outstanding balance {}",
+                    note.net_amt,
+                    outstanding
+                );
+                return Err(InvoiceError::InvalidAmount);
+            }
+            parent.adjustment -= note.net_amt;
+            if note.net_amt == outstanding {
+                Self::transition(env, &mut parent, InvoiceStatus::Credited)?;
+            }
+            symbol_short!("credit")
+        } else {
+            parent.adjustment += note.net_amt;
+            Self::check_amount(env, Self::total(&parent))?;
+            symbol_short!("debit")
+        };
+
+        Self::commit(
+            env,
+            &note,
+            symbol_short!("create"),
+            Some(note.vendor.clone()),
+        );
+        Self::register_invoice_id(env, &note.mongo_id);
+        Self::set_persistent(
+            env,
+            &DataKey::ParentInvoice(note.mongo_id.clone()),
+            &parent_id,
+        );
+        let mut notes = Self::get_notes(env, &parent_id);
+        notes.push_back(note.mongo_id.clone());
+        Self::clear_installments(env, &parent_id);
+        Self::set_persistent(env, &DataKey::Notes(parent_id), &notes);
+        Self::commit(env, &parent, action, Some(parent.vendor.clone()));
+        Ok(note)
+    }
+
+    fn get_notes(env: &Env, mongo_id: &String) -> Vec<String> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Notes(mongo_id.clone()))
+            .unwrap_or(Vec::new(env))
+    }
+
+    /// Credit `parent_id` by the note's amount, which cannot exceed its outstanding balance.
+    /// A credit of the whole balance moves the invoice to `Credited`. Financed invoices cannot
+    /// be credited. Must be authorized by the vendor.
+    pub fn create_credit_note(
+        env: Env,
+        parent_id: String,
+        note: Invoice,
+    ) -> Result<String, InvoiceError> {
+        let note = Self::create_note(&env, parent_id.clone(), note, CREDIT_NOTE_TYPE)?;
+
+        log!(
+            &env,
+            "Credit note {} issued against {}",
+            note.mongo_id,
+            parent_id
+        );
+        env.events().publish(
+            (symbol_short!("Credited"), parent_id),
+            (note.mongo_id, note.net_amt),
+        );
+        Ok(String::from_str(&env, "Credit note created"))
+    }
+
+    /// Charge `parent_id` the note's amount on top of its invoiced amount. Must be
+    /// authorized by the vendor.
+    pub fn create_debit_note(
+        env: Env,
+        parent_id: String,
+        note: Invoice,
+    ) -> Result<String, InvoiceError> {
+        let note = Self::create_note(&env, parent_id.clone(), note, DEBIT_NOTE_TYPE)?;
+
+        log!(
+            &env,
+            "Debit note {} issued against {}",
+            note.mongo_id,
+            parent_id
+        );
+        env.events().publish(
+            (symbol_short!("Debited"), parent_id),
+            (note.mongo_id, note.net_amt),
+        );
+        Ok(String::from_str(&env, "Debit note created"))
+    }
+
+    fn get_template(env: &Env, template_id: u32) -> Option<RecurringTemplate> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Template(template_id))
+    }
+
+    /// Write `n` in decimal at the start of `buf`, returning the number of bytes written
+    fn write_decimal(buf: &mut [u8], n: u32) -> usize {
+        let mut digits = [0u8; 10];
+        let mut start = digits.len();
+        let mut rest = n;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+            if rest == 0 {
+                break;
+            }
+        }
+        let len = digits.len() - start;
+        buf[..len].copy_from_slice(&digits[start..]);
+        len
+    }
+
+    /// `<prefix>-<template_id>-<n>`, the id of the `n`th invoice minted from a template
+    fn recurring_id(env: &Env, prefix: &String, template_id: u32, n: u32) -> String {
+        let len = prefix.len() as usize;
+        let mut buf = [0u8; MAX_ID_PREFIX_LEN + 22];
+        prefix.copy_into_slice(&mut buf[..len]);
+        let mut end = len;
+        for value in [template_id, n] {
+            buf[end] = b'-';
+            end += 1;
+            end += Self::write_decimal(&mut buf[end..], value);
+        }
+        String::from_bytes(env, &buf[..end])
+    }
+
+    /// Refuse a directly created id shaped like `<prefix>-<digits>-<digits>` whose prefix
+    /// belongs to a recurring template, so minted ids cannot be taken ahead of the template
+    fn check_not_recurring_id(env: &Env, mongo_id: &String) -> Result<(), InvoiceError> {
+        let len = mongo_id.len() as usize;
+        if len > MAX_ID_PREFIX_LEN + 22 {
+            return Ok(());
+        }
+        let mut buf = [0u8; MAX_ID_PREFIX_LEN + 22];
+        mongo_id.copy_into_slice(&mut buf[..len]);
+
+        // Walk back over `-<n>` and then `-<template_id>`
+        let mut end = len;
+        for _ in 0..2 {
+            let digits = buf[..end]
+                .iter()
+                .rev()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+            if digits == 0 || digits == end || buf[end - digits - 1] != b'-' {
+                return Ok(());
+            }
+            end -= digits + 1;
+        }
+        let prefix = String::from_bytes(env, &buf[..end]);
+        if env
+            .storage()
+            .persistent()
+            .has(&DataKey::RecurringPrefix(prefix))
+        {
+            log!(
+                env,
+                "Error: Invoice id {} is reserved for a recurring template",
+                mongo_id.clone()
+            );
+            return Err(InvoiceError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// The `n`th invoice of a template, issued at `issue_date`
+    fn recurring_invoice(
+        env: &Env,
+        template: &RecurringTemplate,
+        template_id: u32,
+        n: u32,
+        issue_date: u64,
+    ) -> Result<Invoice, InvoiceError> {
+        let mut invoice = template.invoice.clone();
+        let due_period = invoice
+            .due_date
+            .checked_sub(invoice.creation_date)
+            .ok_or(InvoiceError::InvalidInput)?;
+        invoice.mongo_id = Self::recurring_id(env, &template.invoice.mongo_id, template_id, n);
+        invoice.creation_date = issue_date;
+        invoice.due_date = issue_date
+            .checked_add(due_period)
+            .ok_or(InvoiceError::InvalidInput)?;
+        Self::build_invoice(env, invoice, INVOICE_TYPE, InvoiceStatus::Issued)
+    }
+
+    /// Whether a template with `issued` invoices and `next_issue` pending may mint another
+    fn recurrence_continues(end: &RecurrenceEnd, issued: u32, next_issue: u64) -> bool {
+        match end {
+            RecurrenceEnd::Never => true,
+            RecurrenceEnd::AfterCount(count) => issued < *count,
+            RecurrenceEnd::Until(until) => next_issue <= *until,
+        }
+    }
+
+    /// Register a recurring invoice. `invoice` is validated like `create_invoice`; its
+    /// creation date is the first issue date, and its mongo_id the prefix of the ids it mints.
+    /// A prefix belongs to the first vendor to use it. Must be authorized by the vendor.
+    /// Returns the template id.
+    pub fn create_recurring_template(
+        env: Env,
+        invoice: Invoice,
+        interval: u64,
+        end: RecurrenceEnd,
+    ) -> Result<u32, InvoiceError> {
+        invoice.vendor.require_auth();
+
+        if interval == 0 || invoice.mongo_id.len() as usize > MAX_ID_PREFIX_LEN {
+            log!(&env, "Error: Invalid recurrence");
+            return Err(InvoiceError::InvalidInput);
+        }
+        let template = RecurringTemplate {
+            next_issue: invoice.creation_date,
+            invoice,
+            interval,
+            end,
+            issued: 0,
+            active: true,
+        };
+        if !Self::recurrence_continues(&template.end, 0, template.next_issue) {
+            log!(&env, "Error: Recurrence ends before its first issue");
+            return Err(InvoiceError::InvalidInput);
+        }
+        let prefix = DataKey::RecurringPrefix(template.invoice.mongo_id.clone());
+        let owner: Option<Address> = env.storage().persistent().get(&prefix);
+        if owner
+            .as_ref()
+            .is_some_and(|owner| *owner != template.invoice.vendor)
+        {
+            log!(
+                &env,
+                "Error: Id prefix {} belongs to another vendor's templates",
+                template.invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::Unauthorized);
+        }
+        let template_id: u32 = env
+            .storage()
+            .instance()
+            .get(&DataKey::TemplateCount)
+            .unwrap_or(0);
+        Self::recurring_invoice(&env, &template, template_id, 1, template.next_issue)?;
+
+        Self::set_persistent(&env, &DataKey::Template(template_id), &template);
+        if owner.is_none() {
+            Self::set_persistent(&env, &prefix, &template.invoice.vendor);
+        }
+        env.storage()
+            .instance()
+            .set(&DataKey::TemplateCount, &(template_id + 1));
+        env.storage().instance().extend_ttl(1000, 5000);
+
+        log!(&env, "Recurring template {} created", template_id);
+        env.events().publish(
+            (symbol_short!("Template"), template_id),
+            template.invoice.vendor,
+        );
+        Ok(template_id)
+    }
+
+    /// Stop a template from minting further invoices. Must be authorized by the vendor.
+    pub fn cancel_recurring_template(env: Env, template_id: u32) -> Result<String, InvoiceError> {
+        let mut template = Self::get_template(&env, template_id).ok_or(InvoiceError::NotFound)?;
+        template.invoice.vendor.require_auth();
+
+        template.active = false;
+        Self::set_persistent(&env, &DataKey::Template(template_id), &template);
+
+        log!(&env, "Recurring template {} cancelled", template_id);
+        env.events()
+            .publish((symbol_short!("TplCancel"),), template_id);
+        Ok(String::from_str(&env, "Recurring template cancelled"))
+    }
+
+    /// Mint the next invoice of a template once its issue date has passed. Anyone may call
+    /// this; each call mints at most one invoice, so a keeper behind schedule calls it again.
+    /// Returns the new invoice's mongo_id.
+    pub fn issue_due_recurring(env: Env, template_id: u32) -> Result<String, InvoiceError> {
+        let mut template = Self::get_template(&env, template_id).ok_or(InvoiceError::NotFound)?;
+        if !template.active {
+            log!(
+                &env,
+                "Error: Recurring template {} is not active",
+                template_id
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        if env.ledger().timestamp() < template.next_issue {
+            log!(
+                &env,
+                "Error: Recurring template {} is next due at {}",
+                template_id,
+                template.next_issue
+            );
+            return Err(InvoiceError::NotDue);
+        }
+
+        let invoice = Self::recurring_invoice(
+            &env,
+            &template,
+            template_id,
+            template.issued + 1,
+            template.next_issue,
+        )?;
+        Self::commit(&env, &invoice, symbol_short!("recur"), None);
+        Self::register_invoice_id(&env, &invoice.mongo_id);
+
+        template.issued += 1;
+        template.next_issue = template.next_issue.saturating_add(template.interval);
+        template.active =
+            Self::recurrence_continues(&template.end, template.issued, template.next_issue);
+        Self::set_persistent(&env, &DataKey::Template(template_id), &template);
+
+        log!(
+            &env,
+            "Recurring template {} issued invoice {}",
+            template_id,
+            invoice.mongo_id
+        );
+        env.events().publish(
+            (symbol_short!("Recurred"), template_id),
+            invoice.mongo_id.clone(),
+        );
+        Ok(invoice.mongo_id)
+    }
+
+    fn get_revision(env: &Env, mongo_id: &String) -> u32 {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Revision(mongo_id.clone()))
+            .unwrap_or(0)
+    }
+
+    /// A new due date for `invoice`: not before its creation, at the end of the net period
+    /// of any payment terms, and not before the last installment falls due
+    fn check_due_date(env: &Env, invoice: &Invoice, due_date: u64) -> Result<(), InvoiceError> {
+        if due_date < invoice.creation_date {
+            log!(env, "Error: Due date is before the creation date");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(terms) = Self::get_payment_terms(env, &invoice.mongo_id) {
+            if invoice.creation_date.checked_add(terms.net_period) != Some(due_date) {
+                log!(
+                    env,
+                    "Error: Payment terms of invoice {} fix its due date {} after creation",
+                    invoice.mongo_id.clone(),
+                    terms.net_period
+                );
+                return Err(InvoiceError::InvalidInput);
+            }
+        }
+        if let Some(last) = Self::get_installments(env, &invoice.mongo_id).last() {
+            if due_date < last.due_date {
+                log!(
+                    env,
+                    "Error: Due date is before the last installment due at {}",
+                    last.due_date
+                );
+                return Err(InvoiceError::InvalidInput);
+            }
+        }
+        Ok(())
+    }
+
+    /// Correct an invoice the client has not acknowledged yet. Bumps the invoice's revision,
+    /// which the client names when acknowledging. An amount change drops any installment
+    /// schedule, and a new due date must agree with the payment terms and the installments
+    /// left. Must be authorized by the vendor.
+    pub fn amend_invoice(
+        env: Env,
+        mongo_id: String,
+        patch: InvoicePatch,
+    ) -> Result<u32, InvoiceError> {
+        if mongo_id.is_empty()
+            || (patch.due_date.is_none() && patch.lines.is_none() && patch.fund_reception.is_none())
+        {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if invoice.status != InvoiceStatus::Issued {
+                log!(
+                    &env,
+                    "Error: Invoice {} cannot be amended in status {}",
+                    mongo_id,
+                    invoice.status
+                );
+                return Err(InvoiceError::InvalidTransition);
+            }
+
+            if let Some(lines) = patch.lines {
+                if lines.is_empty() {
+                    log!(&env, "Error: One or more input fields are empty");
+                    return Err(InvoiceError::InvalidInput);
+                }
+                let (subtotal, tax_total) = Self::compute_totals(&env, &lines)?;
+                let net_amt = subtotal
+                    .checked_add(tax_total)
+                    .ok_or(InvoiceError::InvalidAmount)?;
+                Self::check_amount(&env, net_amt)?;
+                // Credit notes already applied must still leave something to pay
+                Self::check_amount(
+                    &env,
+                    net_amt
+                        .checked_add(invoice.adjustment)
+                        .ok_or(InvoiceError::InvalidAmount)?,
+                )?;
+                if net_amt != invoice.net_amt {
+                    Self::clear_installments(&env, &mongo_id);
+                }
+                invoice.lines = lines;
+                invoice.subtotal = subtotal;
+                invoice.tax_total = tax_total;
+                invoice.net_amt = net_amt;
+            }
+            if let Some(due_date) = patch.due_date {
+                Self::check_due_date(&env, &invoice, due_date)?;
+                invoice.due_date = due_date;
+            }
+            if let Some(fund_reception) = patch.fund_reception {
+                invoice.fund_reception = fund_reception;
+            }
+
+            let revision = Self::get_revision(&env, &mongo_id) + 1;
+            Self::set_persistent(&env, &DataKey::Revision(mongo_id.clone()), &revision);
+            invoice.timestamp = env.ledger().timestamp();
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("amend"),
+                Some(invoice.vendor.clone()),
+            );
+
+            log!(
+                &env,
+                "Invoice {} amended to revision {}",
+                mongo_id,
+                revision
+            );
+            env.events().publish(
+                (symbol_short!("Amended"), mongo_id),
+                (invoice.client, revision),
+            );
+            return Ok(revision);
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Acknowledge revision `revision` of an invoice, refusing if the vendor has amended it
+    /// since. Must be authorized by the client.
+    pub fn ack_invoice(
+        env: Env,
+        mongo_id: String,
+        revision: u32,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.client.require_auth();
+        }
+        Self::acknowledge(&env, mongo_id, revision, action, txn_hash)?;
+        Ok(String::from_str(&env, "Invoice acknowledged and updated"))
+    }
+
+    /// Acknowledge up to `MAX_ACK_BATCH` invoices, each checked as in `ack_invoice`.
+    /// Failed items are reported in their result; a missing client authorization fails
+    /// the whole batch.
+    pub fn ack_invoices(
+        env: Env,
+        requests: Vec<AckRequest>,
+    ) -> Result<Vec<BatchResult>, InvoiceError> {
+        Self::check_batch(&env, requests.len(), MAX_ACK_BATCH)?;
+        let mut authorized = Vec::new(&env);
+        for request in requests.iter() {
+            if let Some(invoice) = Self::get_invoice(&env, &request.mongo_id) {
+                Self::require_auth_once(&mut authorized, &invoice.client);
+            }
+        }
+        let mut results = Vec::new(&env);
+        for request in requests.iter() {
+            let result = Self::acknowledge(
+                &env,
+                request.mongo_id.clone(),
+                request.revision,
+                request.action,
+                request.txn_hash,
+            );
+            results.push_back(Self::batch_result(request.mongo_id, result));
+        }
+        Ok(results)
+    }
+
+    fn acknowledge(
+        env: &Env,
+        mongo_id: String,
+        revision: u32,
+        action: String,
+        txn_hash: String,
+    ) -> Result<(), InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(env, &mongo_id) {
+            let current = Self::get_revision(env, &mongo_id);
+            if revision != current {
+                log!(
+                    env,
+                    "Error: Invoice {} is at revision {}, not {}",
+                    mongo_id,
+                    current,
+                    revision
+                );
+                return Err(InvoiceError::RevisionMismatch);
+            }
+            Self::transition(env, &mut invoice, InvoiceStatus::Acknowledged)?;
+
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            // invoice.timestamp=timestamp;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                env,
+                &invoice,
+                symbol_short!("ack"),
+                Some(invoice.client.clone()),
+            );
+
+            log!(env, "Invoice {} acknowledged and updated", mongo_id);
+            env.events()
+                .publish((symbol_short!("Ack"),), mongo_id.clone());
+            return Ok(());
+        }
+
+        log!(env, "Invoice {} not found", mongo_id);
+
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Pay all or part of an invoice on-chain. `payer` must be the invoice's client and must
+    /// authorize. Its tokens are transferred to the vendor's `fund_reception` address, or to
+    /// the financier once the invoice is financed. Escrow-mode invoices lock them in the
+    /// contract instead. The payment is only recorded if the transfer succeeds, and the
+    /// invoice is `PartiallyPaid` until the outstanding balance reaches zero.
+    pub fn pay_invoice(
+        env: Env,
+        mongo_id: String,
+        payer: Address,
+        amount: i128,
+        reference: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            if payer != invoice.client {
+                log!(
+                    &env,
+                    "Error: {} is not the client of invoice {}",
+                    payer,
+                    mongo_id
+                );
+                return Err(InvoiceError::Unauthorized);
+            }
+            payer.require_auth();
+
+            let token = match Self::get_currency_token(&env, &invoice.currency) {
+                Some(token) => token,
+                None => {
+                    log!(
+                        &env,
+                        "Error: Currency {} has no settlement token",
+                        invoice.currency
+                    );
+                    return Err(InvoiceError::UnsupportedCurrency);
+                }
+            };
+            Self::record_payment(&env, &mut invoice, &payer, amount, reference)?;
+
+            if invoice.escrow {
+                token::Client::new(&env, &token).transfer(
+                    &payer,
+                    &env.current_contract_address(),
+                    &amount,
+                );
+                let mut escrow = Self::get_escrow(&env, &mongo_id).unwrap_or(Escrow {
+                    balance: 0,
+                    funded_at: env.ledger().timestamp(),
+                });
+                escrow.balance += amount;
+                Self::save_escrow(&env, &mongo_id, &escrow);
+                env.events().publish(
+                    (symbol_short!("EscrowIn"), mongo_id.clone()),
+                    (payer.clone(), amount),
+                );
+            } else {
+                let token = token::Client::new(&env, &token);
+                for (payee, share) in Self::payees(&env, &mut invoice, amount).iter() {
+                    token.transfer(&payer, &payee, &share);
+                }
+            }
+
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(&env, &invoice, symbol_short!("pay"), Some(payer.clone()));
+
+            log!(&env, "Invoice {} settled on-chain by {}", mongo_id, payer);
+            env.events()
+                .publish((symbol_short!("Settled"), mongo_id), (payer, amount));
+            if invoice.status == InvoiceStatus::Paid {
+                return Ok(String::from_str(&env, "Invoice paid and settled"));
+            }
+            return Ok(String::from_str(&env, "Invoice partially paid"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Attach an installment schedule to an invoice. Tranches must be in increasing due-date
+    /// order and add up to the invoice amount net of notes. Must be authorized by the vendor.
+    pub fn set_installments(
+        env: Env,
+        mongo_id: String,
+        installments: Vec<Installment>,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || installments.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if invoice.status != InvoiceStatus::Issued
+                && invoice.status != InvoiceStatus::Acknowledged
+            {
+                log!(
+                    &env,
+                    "Error: Invoice {} cannot be rescheduled in status {}",
+                    mongo_id,
+                    invoice.status
+                );
+                return Err(InvoiceError::InvalidTransition);
+            }
+
+            let mut schedule = Vec::new(&env);
+            let mut total: i128 = 0;
+            let mut last_due_date: u64 = 0;
+            for installment in installments.iter() {
+                Self::check_amount(&env, installment.amount)?;
+                if installment.due_date <= last_due_date {
+                    log!(&env, "Error: Installment due dates must be increasing");
+                    return Err(InvoiceError::InvalidInput);
+                }
+                last_due_date = installment.due_date;
+                total = total
+                    .checked_add(installment.amount)
+                    .ok_or(InvoiceError::InvalidAmount)?;
+                schedule.push_back(Installment {
+                    due_date: installment.due_date,
+                    amount: installment.amount,
+                    paid: 0,
+                });
+            }
+            if total != Self::total(&invoice) {
+                log!(
+                    &env,
+                    "Error: Installments total {} but invoice amount is {}",
+                    total,
+                    Self::total(&invoice)
+                );
+                return Err(InvoiceError::TotalsMismatch);
+            }
+
+            Self::save_installments(&env, &mongo_id, &schedule);
+
+            log!(&env, "Invoice {} installment schedule set", mongo_id);
+            env.events()
+                .publish((symbol_short!("Schedule"), mongo_id), schedule.len());
+            return Ok(String::from_str(&env, "Installment schedule set"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Attach late-fee terms to an invoice before the client acknowledges it. Must be
+    /// authorized by the vendor.
+    pub fn set_late_fee(
+        env: Env,
+        mongo_id: String,
+        terms: LateFeeTerms,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || terms.period == 0 {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        Self::check_amount(&env, terms.amount)?;
+        if terms.kind == LateFeeKind::Percentage && terms.amount > BPS_DENOMINATOR {
+            log!(&env, "Error: Late fee rate {} exceeds 100%", terms.amount);
+            return Err(InvoiceError::InvalidAmount);
+        }
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if invoice.status != InvoiceStatus::Issued {
+                log!(
+                    &env,
+                    "Error: Late fee terms of invoice {} are fixed once acknowledged",
+                    mongo_id
+                );
+                return Err(InvoiceError::InvalidTransition);
+            }
+
+            let key = DataKey::LateFee(mongo_id.clone());
+            env.storage().persistent().set(&key, &terms);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+
+            log!(&env, "Invoice {} late fee terms set", mongo_id);
+            env.events()
+                .publish((symbol_short!("LateFee"), mongo_id), terms.amount);
+            return Ok(String::from_str(&env, "Late fee terms set"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Attach early-payment terms to an invoice before the client acknowledges it. The due
+    /// date moves to `net_period` after creation. Must be authorized by the vendor.
+    pub fn set_payment_terms(
+        env: Env,
+        mongo_id: String,
+        terms: PaymentTerms,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty()
+            || terms.net_period == 0
+            || terms.discount_period > terms.net_period
+            || terms.discount_rate as i128 >= BPS_DENOMINATOR
+        {
+            log!(&env, "Error: Invalid payment terms");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if invoice.status != InvoiceStatus::Issued {
+                log!(
+                    &env,
+                    "Error: Payment terms of invoice {} are fixed once acknowledged",
+                    mongo_id
+                );
+                return Err(InvoiceError::InvalidTransition);
+            }
+
+            let key = DataKey::PaymentTerms(mongo_id.clone());
+            env.storage().persistent().set(&key, &terms);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+
+            invoice.due_date = invoice
+                .creation_date
+                .checked_add(terms.net_period)
+                .ok_or(InvoiceError::InvalidInput)?;
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("terms"),
+                Some(invoice.vendor.clone()),
+            );
+
+            log!(&env, "Invoice {} payment terms set", mongo_id);
+            env.events().publish(
+                (symbol_short!("Terms"), mongo_id),
+                (terms.discount_rate, terms.discount_period, terms.net_period),
+            );
+            return Ok(String::from_str(&env, "Payment terms set"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Paid an invoice. Must be authorized by the client. Only available for currencies
+    /// without a settlement token; those must go through `pay_invoice`.
+    pub fn paid_invoice(
+        env: Env,
+        mongo_id: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.client.require_auth();
+
+            if Self::get_currency_token(&env, &invoice.currency).is_some() {
+                log!(
+                    &env,
+                    "Error: Invoice {} must be settled with pay_invoice",
+                    mongo_id
+                );
+                return Err(InvoiceError::TokenSettlementRequired);
+            }
+            let client = invoice.client.clone();
+            let remaining = Self::outstanding(&env, &invoice);
+            Self::record_payment(&env, &mut invoice, &client, remaining, txn_hash.clone())?;
+
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            // invoice.timestamp=timestamp;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("paid"),
+                Some(invoice.client.clone()),
+            );
+
+            log!(&env, "Invoice {} paid and updated", mongo_id);
+            env.events()
+                .publish((symbol_short!("Paid"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Invoice paid and updated"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
 
-        state.set(invoice.mongo_id.clone(), invoice.clone());
+    /// Reject an invoice. Must be authorized by the client.
+    pub fn reject_invoice(
+        env: Env,
+        mongo_id: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.client.require_auth();
 
-        Self::save_invoice_storage(&env, &state);
-        Self::save_history_storage(&env, invoice.clone());
+            Self::transition(&env, &mut invoice, InvoiceStatus::Rejected)?;
 
-        log!(&env, "Invoice with mongo_id {} created", invoice.mongo_id);
-        env.events()
-            .publish((symbol_short!("Created"),), invoice.mongo_id.clone());
-        return Ok(String::from_str(&env, "Invoice created"));
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            // invoice.timestamp=timestamp;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("reject"),
+                Some(invoice.client.clone()),
+            );
+
+            log!(&env, "Invoice {} rejected and updated", mongo_id);
+            env.events()
+                .publish((symbol_short!("Rejected"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Invoice rejected and updated"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
     }
 
-    /// Acknowledge an invoice
-    pub fn ack_invoice(
+    /// Void an invoice. Must be authorized by the vendor.
+    pub fn void_invoice(
+        env: Env,
+        mongo_id: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            Self::transition(&env, &mut invoice, InvoiceStatus::Voided)?;
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            // invoice.timestamp=timestamp;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("void"),
+                Some(invoice.vendor.clone()),
+            );
+
+            log!(&env, "Invoice {} voided and updated", mongo_id);
+            env.events()
+                .publish((symbol_short!("Voided"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Invoice voided and updated"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Hide an invoice from the vendor's lists, recording why. Must be authorized by the
+    /// vendor.
+    pub fn delete_sent_invoice(
+        env: Env,
+        mongo_id: String,
+        comment: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || comment.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if invoice.sent_invoice_deleted {
+                log!(&env, "Error: Invoice {} is already deleted", mongo_id);
+                return Err(InvoiceError::InvoiceAlreadyDeleted);
+            }
+
+            invoice.sent_invoice_deleted = true;
+            invoice.deleted_comments = comment;
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("del_sent"),
+                Some(invoice.vendor.clone()),
+            );
+
+            log!(&env, "Invoice {} deleted by vendor", mongo_id);
+            env.events()
+                .publish((symbol_short!("DelSent"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Sent invoice deleted"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Hide an invoice from the client's lists, recording why. Must be authorized by the
+    /// client.
+    pub fn delete_received_invoice(
+        env: Env,
+        mongo_id: String,
+        comment: String,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || comment.is_empty() || action.is_empty() || txn_hash.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.client.require_auth();
+
+            if invoice.received_invoice_deleted {
+                log!(&env, "Error: Invoice {} is already deleted", mongo_id);
+                return Err(InvoiceError::InvoiceAlreadyDeleted);
+            }
+
+            invoice.received_invoice_deleted = true;
+            invoice.deleted_comments = comment;
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("del_recv"),
+                Some(invoice.client.clone()),
+            );
+
+            log!(&env, "Invoice {} deleted by client", mongo_id);
+            env.events()
+                .publish((symbol_short!("DelRecv"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Received invoice deleted"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Undo `party`'s deletion of an invoice. `party` must be the invoice's vendor or client
+    /// and must authorize. The deletion comment is cleared once neither side has it deleted.
+    pub fn restore_invoice(
         env: Env,
         mongo_id: String,
+        party: Address,
         action: String,
         txn_hash: String,
     ) -> Result<String, InvoiceError> {
@@ -256,322 +3809,858 @@ impl InvoiceContract {
             log!(&env, "Error: One or more input fields are empty");
             return Err(InvoiceError::InvalidInput);
         }
-        let mut state = Self::get_invoice_storage(&env);
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            party.require_auth();
+
+            let deleted = if party == invoice.vendor {
+                &mut invoice.sent_invoice_deleted
+            } else if party == invoice.client {
+                &mut invoice.received_invoice_deleted
+            } else {
+                log!(
+                    &env,
+                    "Error: {} is not a party to invoice {}",
+                    party,
+                    mongo_id
+                );
+                return Err(InvoiceError::Unauthorized);
+            };
+            if !*deleted {
+                log!(&env, "Error: Invoice {} is not deleted", mongo_id);
+                return Err(InvoiceError::InvalidInput);
+            }
+            *deleted = false;
+
+            if !invoice.sent_invoice_deleted && !invoice.received_invoice_deleted {
+                invoice.deleted_comments = String::from_str(&env, "");
+            }
+            invoice.action = action;
+            invoice.previous_invoice_hash = invoice.txn_hash.clone();
+            invoice.txn_hash = txn_hash;
+            invoice.timestamp = env.ledger().timestamp();
+
+            Self::commit(&env, &invoice, symbol_short!("restore"), Some(party));
+
+            log!(&env, "Invoice {} restored", mongo_id);
+            env.events()
+                .publish((symbol_short!("Restored"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Invoice restored"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    fn get_dispute(env: &Env, mongo_id: &String) -> Option<Dispute> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::Dispute(mongo_id.clone()))
+    }
+
+    fn save_dispute(env: &Env, mongo_id: &String, dispute: &Dispute) {
+        let key = DataKey::Dispute(mongo_id.clone());
+        env.storage().persistent().set(&key, dispute);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+    }
+
+    /// Load a disputed invoice and its dispute, checking the dispute is in `state`
+    fn get_disputed(
+        env: &Env,
+        mongo_id: &String,
+        state: DisputeState,
+    ) -> Result<(Invoice, Dispute), InvoiceError> {
+        let invoice = Self::get_invoice(env, mongo_id).ok_or(InvoiceError::NotFound)?;
+        let dispute = match Self::get_dispute(env, mongo_id) {
+            Some(dispute) if invoice.status == InvoiceStatus::Disputed => dispute,
+            _ => {
+                log!(env, "Error: Invoice {} is not disputed", mongo_id.clone());
+                return Err(InvoiceError::NotFound);
+            }
+        };
+        if dispute.state != state {
+            log!(
+                env,
+                "Error: Dispute on invoice {} is {}",
+                mongo_id.clone(),
+                dispute.state
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        Ok((invoice, dispute))
+    }
+
+    /// Disputed escrow waits for the dispute's settlement rather than a confirmation or refund
+    fn check_not_disputed(env: &Env, invoice: &Invoice) -> Result<(), InvoiceError> {
+        if invoice.status == InvoiceStatus::Disputed {
+            log!(
+                env,
+                "Error: Invoice {} is disputed",
+                invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::InvalidTransition);
+        }
+        Ok(())
+    }
+
+    /// Once a financier has paid for an invoice, vendor and client can no longer reduce what
+    /// it collects between themselves
+    fn check_not_financed(env: &Env, invoice: &Invoice) -> Result<(), InvoiceError> {
+        if !invoice.financing_details.is_empty() {
+            log!(
+                env,
+                "Error: Invoice {} is financed",
+                invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::InvoiceFinanced);
+        }
+        Ok(())
+    }
+
+    /// Require `party` to be the invoice's vendor or client and to authorize
+    fn require_party(env: &Env, invoice: &Invoice, party: &Address) -> Result<(), InvoiceError> {
+        if *party != invoice.vendor && *party != invoice.client {
+            log!(
+                env,
+                "Error: {} is not a party to invoice {}",
+                party.clone(),
+                invoice.mongo_id.clone()
+            );
+            return Err(InvoiceError::Unauthorized);
+        }
+        party.require_auth();
+        Ok(())
+    }
+
+    /// A settlement amount cannot raise the invoice, and must cover what was already paid
+    /// unless those payments are still in escrow and can be returned
+    fn check_settlement(env: &Env, invoice: &Invoice, amount: i128) -> Result<(), InvoiceError> {
+        let floor = if Self::get_escrow(env, &invoice.mongo_id).is_some() {
+            0
+        } else {
+            invoice.amount_paid
+        };
+        if amount < floor || amount > Self::total(invoice) {
+            log!(
+                env,
+                "Error: Settlement {} must be between {} and {} invoiced",
+                amount,
+                floor,
+                Self::total(invoice)
+            );
+            return Err(InvoiceError::InvalidAmount);
+        }
+        Ok(())
+    }
+
+    /// Settle the invoice at `amount`. Escrowed payments above it go back to the client, and
+    /// once nothing is left to pay the rest of the escrow is released. The invoice returns to
+    /// where it was before the dispute, or moves on to `Paid`, or to `PaymentConfirmed` or
+    /// `Refunded` when its escrow was settled. Returns the fund movements.
+    fn resolve_dispute(
+        env: &Env,
+        invoice: &mut Invoice,
+        dispute: &mut Dispute,
+        amount: i128,
+    ) -> Result<Vec<InvoiceChange>, InvoiceError> {
+        let mut moves = Vec::new(env);
+        if amount != Self::total(invoice) {
+            invoice.adjustment += amount - Self::total(invoice);
+            Self::clear_installments(env, &invoice.mongo_id);
+        }
+        let escrowed = Self::get_escrow(env, &invoice.mongo_id).is_some();
+        if escrowed && invoice.amount_paid > amount {
+            let excess = invoice.amount_paid - amount;
+            moves.push_back(Self::return_escrow(env, invoice, excess)?);
+        }
+
+        let settled = Self::outstanding(env, invoice) <= 0;
+        let status = if escrowed && invoice.amount_paid == 0 {
+            InvoiceStatus::Refunded
+        } else if escrowed && settled {
+            moves.append(&Self::release_escrow(env, invoice)?);
+            InvoiceStatus::PaymentConfirmed
+        } else if settled {
+            InvoiceStatus::Paid
+        } else {
+            dispute.resume_status
+        };
+        Self::transition(env, invoice, status)?;
+        dispute.state = DisputeState::Resolved;
+        Ok(moves)
+    }
+
+    /// Dispute an invoice, blocking payment, financing and escrow refunds until it is
+    /// resolved. The client may dispute an open invoice. Once payments are held in escrow
+    /// either party may dispute them, which gives the vendor a claim against a client who
+    /// would otherwise wait out the escrow timeout. Financed invoices cannot be disputed, as
+    /// a settlement would cut what the financier collects. `party` must authorize.
+    pub fn open_dispute(
+        env: Env,
+        mongo_id: String,
+        party: Address,
+        reason_code: u32,
+        evidence_hash: BytesN<32>,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            Self::require_party(&env, &invoice, &party)?;
+            Self::check_not_financed(&env, &invoice)?;
+            let escrowed = Self::get_escrow(&env, &mongo_id).is_some();
+            if (party == invoice.vendor || invoice.status == InvoiceStatus::Paid) && !escrowed {
+                log!(
+                    &env,
+                    "Error: Invoice {} has no escrowed payments to dispute",
+                    mongo_id
+                );
+                return Err(InvoiceError::InvalidTransition);
+            }
+
+            let resume_status = invoice.status;
+            Self::transition(&env, &mut invoice, InvoiceStatus::Disputed)?;
+            let dispute = Dispute {
+                reason_code,
+                evidence_hash,
+                opened_at: env.ledger().timestamp(),
+                resume_status,
+                state: DisputeState::Open,
+                offer_amount: 0,
+                offer_by: None,
+                arbiter: None,
+            };
+            Self::save_dispute(&env, &mongo_id, &dispute);
+
+            Self::commit_with(
+                &env,
+                &invoice,
+                symbol_short!("dispute"),
+                Some(party),
+                Vec::from_array(&env, [InvoiceChange::Dispute(dispute)]),
+            );
+
+            log!(&env, "Invoice {} disputed", mongo_id);
+            env.events()
+                .publish((symbol_short!("Disputed"), mongo_id), reason_code);
+            return Ok(String::from_str(&env, "Dispute opened"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Propose settling an open dispute at `amount`, replacing any earlier offer. `party`
+    /// must be the vendor or the client and must authorize.
+    pub fn counter_offer(
+        env: Env,
+        mongo_id: String,
+        party: Address,
+        amount: i128,
+    ) -> Result<String, InvoiceError> {
+        let (invoice, mut dispute) = Self::get_disputed(&env, &mongo_id, DisputeState::Open)?;
+        Self::require_party(&env, &invoice, &party)?;
+        Self::check_settlement(&env, &invoice, amount)?;
+
+        dispute.offer_amount = amount;
+        dispute.offer_by = Some(party.clone());
+        Self::save_dispute(&env, &mongo_id, &dispute);
+        Self::commit_with(
+            &env,
+            &invoice,
+            symbol_short!("counter"),
+            Some(party.clone()),
+            Vec::from_array(&env, [InvoiceChange::Dispute(dispute)]),
+        );
+
+        log!(&env, "Invoice {} counter-offer of {}", mongo_id, amount);
+        env.events()
+            .publish((symbol_short!("Counter"), mongo_id), (party, amount));
+        Ok(String::from_str(&env, "Counter-offer made"))
+    }
+
+    /// Accept the other party's offer, settling the dispute at the offered amount
+    pub fn accept_offer(
+        env: Env,
+        mongo_id: String,
+        party: Address,
+    ) -> Result<String, InvoiceError> {
+        let (mut invoice, mut dispute) = Self::get_disputed(&env, &mongo_id, DisputeState::Open)?;
+        Self::require_party(&env, &invoice, &party)?;
+        match &dispute.offer_by {
+            None => {
+                log!(&env, "Error: No offer on invoice {}", mongo_id);
+                return Err(InvoiceError::InvalidInput);
+            }
+            Some(offer_by) if *offer_by == party => {
+                log!(&env, "Error: {} cannot accept its own offer", party);
+                return Err(InvoiceError::Unauthorized);
+            }
+            Some(_) => {}
+        }
+
+        let amount = dispute.offer_amount;
+        let mut moves = Self::resolve_dispute(&env, &mut invoice, &mut dispute, amount)?;
+        Self::save_dispute(&env, &mongo_id, &dispute);
+        moves.push_front(InvoiceChange::Dispute(dispute));
+        Self::commit_with(&env, &invoice, symbol_short!("agree"), Some(party), moves);
+
+        log!(&env, "Invoice {} dispute settled at {}", mongo_id, amount);
+        env.events()
+            .publish((symbol_short!("Agreed"), mongo_id), amount);
+        Ok(String::from_str(&env, "Dispute settled"))
+    }
+
+    /// Ask for an arbiter on an open dispute. Either party may escalate; the arbiter is then
+    /// assigned by the admin, so neither side picks who rules.
+    pub fn escalate_dispute(
+        env: Env,
+        mongo_id: String,
+        party: Address,
+    ) -> Result<String, InvoiceError> {
+        let (invoice, mut dispute) = Self::get_disputed(&env, &mongo_id, DisputeState::Open)?;
+        Self::require_party(&env, &invoice, &party)?;
+
+        dispute.state = DisputeState::Escalated;
+        Self::save_dispute(&env, &mongo_id, &dispute);
+        Self::commit_with(
+            &env,
+            &invoice,
+            symbol_short!("escalate"),
+            Some(party.clone()),
+            Vec::from_array(&env, [InvoiceChange::Dispute(dispute)]),
+        );
+
+        log!(&env, "Invoice {} dispute escalated by {}", mongo_id, party);
+        env.events()
+            .publish((symbol_short!("Escalated"), mongo_id), party);
+        Ok(String::from_str(&env, "Dispute escalated"))
+    }
+
+    /// Assign a registered arbiter to an escalated dispute, replacing any earlier one that
+    /// has not ruled yet. Must be authorized by the admin.
+    pub fn assign_arbiter(
+        env: Env,
+        mongo_id: String,
+        arbiter: Address,
+    ) -> Result<String, InvoiceError> {
+        let admin = Self::require_admin(&env)?;
+        let (invoice, mut dispute) = Self::get_disputed(&env, &mongo_id, DisputeState::Escalated)?;
+        if !Self::is_arbiter(&env, &arbiter) {
+            log!(&env, "Error: {} is not an arbiter", arbiter);
+            return Err(InvoiceError::ArbiterNotFound);
+        }
+
+        dispute.arbiter = Some(arbiter.clone());
+        Self::save_dispute(&env, &mongo_id, &dispute);
+        Self::commit_with(
+            &env,
+            &invoice,
+            symbol_short!("arbiter"),
+            Some(admin),
+            Vec::from_array(&env, [InvoiceChange::Dispute(dispute)]),
+        );
 
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-           
+        log!(&env, "Invoice {} dispute assigned to {}", mongo_id, arbiter);
+        env.events()
+            .publish((symbol_short!("Arbiter"), mongo_id), arbiter);
+        Ok(String::from_str(&env, "Arbiter assigned"))
+    }
 
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-            ) {
-                return Err(error);
+    /// Final ruling on an escalated dispute. Must be authorized by the arbiter the admin
+    /// assigned. A ruling below what the client paid into escrow refunds the difference.
+    pub fn rule_dispute(env: Env, mongo_id: String, amount: i128) -> Result<String, InvoiceError> {
+        let (mut invoice, mut dispute) =
+            Self::get_disputed(&env, &mongo_id, DisputeState::Escalated)?;
+        let arbiter = match dispute.arbiter.clone() {
+            Some(arbiter) => arbiter,
+            None => {
+                log!(&env, "Error: No arbiter assigned to invoice {}", mongo_id);
+                return Err(InvoiceError::ArbiterNotFound);
             }
+        };
+        arbiter.require_auth();
+        Self::check_settlement(&env, &invoice, amount)?;
+
+        let mut moves = Self::resolve_dispute(&env, &mut invoice, &mut dispute, amount)?;
+        Self::save_dispute(&env, &mongo_id, &dispute);
+        moves.push_front(InvoiceChange::Dispute(dispute));
+        Self::commit_with(
+            &env,
+            &invoice,
+            symbol_short!("ruling"),
+            Some(arbiter),
+            moves,
+        );
+
+        log!(&env, "Invoice {} dispute ruled at {}", mongo_id, amount);
+        env.events()
+            .publish((symbol_short!("Ruling"), mongo_id), amount);
+        Ok(String::from_str(&env, "Dispute ruled"))
+    }
+
+    /// Finance an invoice directly, outside the bid marketplace. The advance is transferred
+    /// from the financier to the vendor and client payments are routed to the financier until
+    /// the advance and fee are repaid. Must be authorized by both the financier and the vendor, and the financier
+    /// must be registered by the admin through `register_financier`.
+    pub fn finance_invoice(
+        env: Env,
+        mongo_id: String,
+        financier: Address,
+        finance_id: String,
+        advance: i128,
+        fee: i128,
+        action: String,
+        txn_hash: String,
+    ) -> Result<String, InvoiceError> {
+        if mongo_id.is_empty() || action.is_empty() || finance_id.is_empty() || txn_hash.is_empty()
+        {
+            log!(&env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        financier.require_auth();
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            let token = Self::financing_token(&env, &invoice, &financier)?;
+            Self::check_financing_terms(&env, &invoice, &financier, advance, fee)?;
+
+            let record = FinancingRecord {
+                finance_id,
+                financier: financier.clone(),
+                advance,
+                fee,
+                timestamp: env.ledger().timestamp(),
+                repaid: 0,
+                written_off: 0,
+            };
+            Self::apply_financing(&env, &mut invoice, record)?;
+            token::Client::new(&env, &token).transfer(
+                &financier,
+                &invoice.fund_reception,
+                &advance,
+            );
 
             invoice.action = action;
-            invoice.ack = true;
             invoice.previous_invoice_hash = invoice.txn_hash.clone();
             invoice.txn_hash = txn_hash;
             // invoice.timestamp=timestamp;
             invoice.timestamp = env.ledger().timestamp();
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("finance"),
+                Some(financier.clone()),
+            );
+
+            log!(&env, "Invoice {} financed by {}", mongo_id, financier);
+            env.events()
+                .publish((symbol_short!("Finance"),), mongo_id.clone());
+            return Ok(String::from_str(&env, "Invoice financed"));
+        }
+
+        log!(&env, "Invoice {} not found", mongo_id);
+        Err(InvoiceError::NotFound)
+    }
+
+    /// List an acknowledged invoice on the factoring marketplace so financiers can bid on it.
+    /// Must be authorized by the vendor.
+    pub fn list_for_financing(env: Env, mongo_id: String) -> Result<String, InvoiceError> {
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
 
-            log!(&env, "Invoice {} acknowledged and updated", mongo_id);
-            env.events()
-                .publish((symbol_short!("Ack"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice acknowledged and updated"));
+            if invoice.status != InvoiceStatus::Acknowledged {
+                log!(
+                    &env,
+                    "Error: Invoice {} cannot be listed in status {}",
+                    mongo_id,
+                    invoice.status
+                );
+                return Err(InvoiceError::InvoiceNotAcknowledged);
+            }
+            if Self::get_currency_token(&env, &invoice.currency).is_none() {
+                log!(
+                    &env,
+                    "Error: Currency {} has no settlement token",
+                    invoice.currency
+                );
+                return Err(InvoiceError::UnsupportedCurrency);
+            }
+            let key = DataKey::Listing(mongo_id.clone());
+            env.storage().persistent().set(&key, &true);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, INVOICE_TTL_THRESHOLD, INVOICE_TTL_EXTEND);
+
+            log!(&env, "Invoice {} listed for financing", mongo_id);
+            env.events().publish((symbol_short!("Listed"),), mongo_id);
+            return Ok(String::from_str(&env, "Invoice listed for financing"));
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
-
-        return Err(InvoiceError::NotFound);
+        Err(InvoiceError::NotFound)
     }
 
-    /// Paid an invoice
-    pub fn paid_invoice(
+    /// Bid on a listed invoice. The advance (`advance_rate` of the outstanding balance) is
+    /// locked in the contract until the bid is accepted, loses or is withdrawn. Returns the
+    /// bid id. Must be authorized by the financier.
+    pub fn submit_bid(
         env: Env,
+        financier: Address,
         mongo_id: String,
-        action: String,
-        txn_hash: String,
-    ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty()  {
-            log!(&env, "Error: One or more input fields are empty");
-            return Err(InvoiceError::InvalidInput);
-        }
-        let mut state = Self::get_invoice_storage(&env);
+        advance_rate: u32,
+        fee_rate: u32,
+        expiry: u64,
+    ) -> Result<u32, InvoiceError> {
+        financier.require_auth();
 
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                true,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-            ) {
-                return Err(error);
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            if !Self::is_listed(&env, &mongo_id) || invoice.status != InvoiceStatus::Acknowledged {
+                log!(
+                    &env,
+                    "Error: Invoice {} is not listed for financing",
+                    mongo_id
+                );
+                return Err(InvoiceError::NotListed);
+            }
+            if expiry <= env.ledger().timestamp() {
+                log!(&env, "Error: Bid expiry {} is in the past", expiry);
+                return Err(InvoiceError::BidExpired);
             }
+            if advance_rate == 0 || advance_rate as i128 + fee_rate as i128 > BPS_DENOMINATOR {
+                log!(
+                    &env,
+                    "Error: Invalid advance rate {} and fee rate {}",
+                    advance_rate,
+                    fee_rate
+                );
+                return Err(InvoiceError::InvalidInput);
+            }
+            let token = Self::financing_token(&env, &invoice, &financier)?;
 
-            invoice.action = action;
-            invoice.paid = true;
-            invoice.ack = true;
-            invoice.previous_invoice_hash = invoice.txn_hash.clone();
-            invoice.txn_hash = txn_hash;
-            // invoice.timestamp=timestamp;
-            invoice.timestamp = env.ledger().timestamp();
+            let outstanding = Self::outstanding(&env, &invoice);
+            let advance = outstanding * advance_rate as i128 / BPS_DENOMINATOR;
+            let fee = outstanding * fee_rate as i128 / BPS_DENOMINATOR;
+            Self::check_financing_terms(&env, &invoice, &financier, advance, fee)?;
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            token::Client::new(&env, &token).transfer(
+                &financier,
+                &env.current_contract_address(),
+                &advance,
+            );
 
-            log!(&env, "Invoice {} paid and updated", mongo_id);
-            env.events()
-                .publish((symbol_short!("Paid"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice paid and updated"));
+            let bid_id: u32 = env
+                .storage()
+                .persistent()
+                .get(&DataKey::NextBidId(mongo_id.clone()))
+                .unwrap_or(0);
+            let mut bids = Self::get_bids(&env, &mongo_id);
+            bids.push_back(Bid {
+                bid_id,
+                financier: financier.clone(),
+                advance_rate,
+                fee_rate,
+                expiry,
+                advance,
+                fee,
+            });
+            Self::save_bids(&env, &mongo_id, &bids);
+            let next_key = DataKey::NextBidId(mongo_id.clone());
+            env.storage().persistent().set(&next_key, &(bid_id + 1));
+            env.storage().persistent().extend_ttl(
+                &next_key,
+                INVOICE_TTL_THRESHOLD,
+                INVOICE_TTL_EXTEND,
+            );
+
+            log!(&env, "Bid {} submitted on invoice {}", bid_id, mongo_id);
+            env.events().publish(
+                (symbol_short!("Bid"), mongo_id),
+                (financier, bid_id, advance),
+            );
+            return Ok(bid_id);
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
-    /// Reject an invoice
-    pub fn reject_invoice(
+    /// Withdraw a bid that has not been accepted and get its locked advance back. Must be
+    /// authorized by the financier who placed it.
+    pub fn withdraw_bid(
         env: Env,
+        financier: Address,
         mongo_id: String,
-        action: String,
-        txn_hash: String,
+        bid_id: u32,
     ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
-            log!(&env, "Error: One or more input fields are empty");
-            return Err(InvoiceError::InvalidInput);
-        }
-        let mut state = Self::get_invoice_storage(&env);
-
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                true,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-            ) {
-                return Err(error);
-            }
+        financier.require_auth();
 
-            invoice.action = action;
-            invoice.rejected = true;
-            invoice.previous_invoice_hash = invoice.txn_hash.clone();
-            invoice.txn_hash = txn_hash;
-            // invoice.timestamp=timestamp;
-            invoice.timestamp = env.ledger().timestamp();
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            let mut bids = Self::get_bids(&env, &mongo_id);
+            let index = match bids
+                .iter()
+                .position(|bid| bid.bid_id == bid_id && bid.financier == financier)
+            {
+                Some(index) => index as u32,
+                None => {
+                    log!(
+                        &env,
+                        "Error: Bid {} not found on invoice {}",
+                        bid_id,
+                        mongo_id
+                    );
+                    return Err(InvoiceError::NotFound);
+                }
+            };
+            let bid = bids.get(index).unwrap();
+            bids.remove(index);
+            Self::save_bids(&env, &mongo_id, &bids);
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            let token = Self::escrow_token(&env, &invoice)?;
+            token::Client::new(&env, &token).transfer(
+                &env.current_contract_address(),
+                &financier,
+                &bid.advance,
+            );
 
-            log!(&env, "Invoice {} rejected and updated", mongo_id);
+            log!(&env, "Bid {} withdrawn from invoice {}", bid_id, mongo_id);
             env.events()
-                .publish((symbol_short!("Rejected"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice rejected and updated"));
+                .publish((symbol_short!("BidWthdrw"), mongo_id), (financier, bid_id));
+            return Ok(String::from_str(&env, "Bid withdrawn"));
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
-    /// Void an invoice
-    pub fn void_invoice(
+    /// Accept a bid on a listed invoice. The bid's locked advance is paid to the vendor,
+    /// every other bid is refunded and client payments are routed to the winning financier
+    /// until the advance and fee are repaid. Must be authorized by the vendor.
+    pub fn accept_bid(
         env: Env,
         mongo_id: String,
+        bid_id: u32,
         action: String,
         txn_hash: String,
     ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty(){
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
             log!(&env, "Error: One or more input fields are empty");
             return Err(InvoiceError::InvalidInput);
         }
-        let mut state = Self::get_invoice_storage(&env);
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.vendor.require_auth();
+
+            if !Self::is_listed(&env, &mongo_id) {
+                log!(
+                    &env,
+                    "Error: Invoice {} is not listed for financing",
+                    mongo_id
+                );
+                return Err(InvoiceError::NotListed);
+            }
+            let bids = Self::get_bids(&env, &mongo_id);
+            let bid = match bids.iter().find(|bid| bid.bid_id == bid_id) {
+                Some(bid) => bid,
+                None => {
+                    log!(
+                        &env,
+                        "Error: Bid {} not found on invoice {}",
+                        bid_id,
+                        mongo_id
+                    );
+                    return Err(InvoiceError::NotFound);
+                }
+            };
+            if bid.expiry <= env.ledger().timestamp() {
+                log!(&env, "Error: Bid {} expired at {}", bid_id, bid.expiry);
+                return Err(InvoiceError::BidExpired);
+            }
+            Self::check_financing_terms(&env, &invoice, &bid.financier, bid.advance, bid.fee)?;
+
+            let record = FinancingRecord {
+                finance_id: txn_hash.clone(),
+                financier: bid.financier.clone(),
+                advance: bid.advance,
+                fee: bid.fee,
+                timestamp: env.ledger().timestamp(),
+                repaid: 0,
+                written_off: 0,
+            };
+            Self::apply_financing(&env, &mut invoice, record)?;
 
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                true,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-                false,
-            ) {
-                return Err(error);
+            let token = token::Client::new(&env, &Self::escrow_token(&env, &invoice)?);
+            token.transfer(
+                &env.current_contract_address(),
+                &invoice.fund_reception,
+                &bid.advance,
+            );
+            for other in bids.iter() {
+                if other.bid_id != bid_id {
+                    token.transfer(
+                        &env.current_contract_address(),
+                        &other.financier,
+                        &other.advance,
+                    );
+                }
             }
+            env.storage()
+                .persistent()
+                .remove(&DataKey::Bids(mongo_id.clone()));
+
             invoice.action = action;
-            invoice.voided = true;
             invoice.previous_invoice_hash = invoice.txn_hash.clone();
             invoice.txn_hash = txn_hash;
-            // invoice.timestamp=timestamp;
             invoice.timestamp = env.ledger().timestamp();
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            Self::commit(
+                &env,
+                &invoice,
+                symbol_short!("accept"),
+                Some(invoice.vendor.clone()),
+            );
 
-            log!(&env, "Invoice {} voided and updated", mongo_id);
-            env.events()
-                .publish((symbol_short!("Voided"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice voided and updated"));
+            log!(&env, "Bid {} accepted on invoice {}", bid_id, mongo_id);
+            env.events().publish(
+                (symbol_short!("Finance"), mongo_id.clone()),
+                (bid.financier, bid_id, bid.advance),
+            );
+            return Ok(String::from_str(&env, "Bid accepted and invoice financed"));
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
-    /// Finance an invoice
-    pub fn finance_invoice(
+    /// Payment Confirmation an invoice. Must be authorized by the vendor, or for escrow-mode
+    /// invoices by the client, which releases the escrowed funds to the vendor.
+    pub fn payment_confirmation_invoice(
         env: Env,
         mongo_id: String,
-        finance_id: String,
         action: String,
         txn_hash: String,
     ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || action.is_empty() || finance_id.is_empty() || txn_hash.is_empty()
-        {
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
             log!(&env, "Error: One or more input fields are empty");
             return Err(InvoiceError::InvalidInput);
         }
-        let mut state = Self::get_invoice_storage(&env);
-
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-        
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                true,
-                false,
-                false,
-                false,
-                false,
-                false,
-                true,
-                false,
-                false,
-            ) {
-                return Err(error);
-            }
-
-            let finance_len= invoice.financing_details.len();
-            for i in 0..finance_len {
-                let key = invoice.financing_details.get(i).unwrap(); 
-                if key==finance_id {
-                    log!(&env, "Duplicate finance_id {} for invoice {}", finance_id, mongo_id);
-                    return Err(InvoiceError::AlreadyFinanced);
-                }
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            let confirmer = if invoice.escrow {
+                invoice.client.clone()
+            } else {
+                invoice.vendor.clone()
+            };
+            confirmer.require_auth();
+
+            Self::check_not_disputed(&env, &invoice)?;
+            if invoice.status != InvoiceStatus::Paid {
+                log!(&env, "Error: Invoice {} is not paid", mongo_id);
+                return Err(InvoiceError::InvalidTransition);
             }
 
+            let moves = if invoice.escrow {
+                Self::release_escrow(&env, &mut invoice)?
+            } else {
+                Vec::new(&env)
+            };
+            Self::transition(&env, &mut invoice, InvoiceStatus::PaymentConfirmed)?;
+
             invoice.action = action;
-            invoice.finance = true;
-            invoice.financing_details.push_back(finance_id);
             invoice.previous_invoice_hash = invoice.txn_hash.clone();
             invoice.txn_hash = txn_hash;
             // invoice.timestamp=timestamp;
             invoice.timestamp = env.ledger().timestamp();
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            Self::commit_with(
+                &env,
+                &invoice,
+                symbol_short!("confirm"),
+                Some(confirmer),
+                moves,
+            );
 
-            log!(&env, "Invoice {} finance request initiated", mongo_id);
+            log!(
+                &env,
+                "Invoice {} payment confirmation and updated",
+                mongo_id
+            );
             env.events()
-                .publish((symbol_short!("Finance"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice finance request initiated"));
+                .publish((symbol_short!("Payment"),), mongo_id.clone());
+            return Ok(String::from_str(
+                &env,
+                "Invoice payment confirmation and updated",
+            ));
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
-    /// Payment Confirmation an invoice
-    pub fn payment_confirmation_invoice(
+    /// Refund the escrowed payments of an invoice to their payers once the escrow timeout has
+    /// elapsed without a confirmation. Must be authorized by the client.
+    pub fn refund_escrow(
         env: Env,
         mongo_id: String,
         action: String,
         txn_hash: String,
     ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty(){
+        if mongo_id.is_empty() || action.is_empty() || txn_hash.is_empty() {
             log!(&env, "Error: One or more input fields are empty");
             return Err(InvoiceError::InvalidInput);
         }
-        let mut state = Self::get_invoice_storage(&env);
+        if let Some(mut invoice) = Self::get_invoice(&env, &mongo_id) {
+            invoice.client.require_auth();
+            Self::check_not_disputed(&env, &invoice)?;
 
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-            if let Some(error) = Self::check_invoice_status(
-                invoice.clone(),
-                true,
-                true,
-                true,
-                false,
-                false,
-                false,
-                true,
-                true,
-                true,
-            ) {
-                return Err(error);
+            let escrow = match Self::get_escrow(&env, &mongo_id) {
+                Some(escrow) => escrow,
+                None => {
+                    log!(&env, "Error: Invoice {} has no escrowed funds", mongo_id);
+                    return Err(InvoiceError::NotFound);
+                }
+            };
+            let unlock_at = escrow
+                .funded_at
+                .saturating_add(Self::get_escrow_timeout(&env));
+            if env.ledger().timestamp() < unlock_at {
+                log!(
+                    &env,
+                    "Error: Escrow for invoice {} is locked until {}",
+                    mongo_id,
+                    unlock_at
+                );
+                return Err(InvoiceError::EscrowLocked);
             }
 
+            let moves = Self::refund_escrowed(&env, &mut invoice)?;
+
             invoice.action = action;
-            invoice.paid = true;
-            invoice.payment_confirmation = true;
             invoice.previous_invoice_hash = invoice.txn_hash.clone();
             invoice.txn_hash = txn_hash;
-            // invoice.timestamp=timestamp;
             invoice.timestamp = env.ledger().timestamp();
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
-
-            log!(
+            Self::commit_with(
                 &env,
-                "Invoice {} payment confirmation and updated",
-                mongo_id
+                &invoice,
+                symbol_short!("refund"),
+                Some(invoice.client.clone()),
+                moves,
             );
-            env.events()
-                .publish((symbol_short!("Payment"),), mongo_id.clone());
-            return Ok(String::from_str(
-                &env,
-                "Invoice payment confirmation and updated",
-            ));
+
+            log!(&env, "Invoice {} escrow refunded", mongo_id);
+            return Ok(String::from_str(&env, "Invoice escrow refunded"));
         }
 
         log!(&env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
-    ///  Update Tracking an invoice
+    ///  Update Tracking an invoice. Must be authorized by a registered operator.
     pub fn update_invoice_tracking(
         env: Env,
+        operator: Address,
         mongo_id: String,
         subject: String,
         status: String,
@@ -580,155 +4669,636 @@ impl InvoiceContract {
         event: String,
         to: String,
     ) -> Result<String, InvoiceError> {
-        if mongo_id.is_empty() || event.is_empty() {
-            log!(&env, "Error: One or more input fields are empty");
-            return Err(InvoiceError::InvalidInput);
+        Self::require_operator(&env, &operator)?;
+        let tracking = Track {
+            subject,
+            status,
+            api_key_id,
+            event,
+            msg_id,
+            to,
+        };
+        Self::track(&env, &operator, mongo_id, tracking)?;
+        Ok(String::from_str(&env, "Invoice tracking updated"))
+    }
+
+    /// Apply up to `MAX_TRACKING_BATCH` tracking updates, each checked as in
+    /// `update_invoice_tracking`. Must be authorized by a registered operator; failed
+    /// items are reported in their result.
+    pub fn update_tracking_batch(
+        env: Env,
+        operator: Address,
+        updates: Vec<TrackingUpdate>,
+    ) -> Result<Vec<BatchResult>, InvoiceError> {
+        Self::check_batch(&env, updates.len(), MAX_TRACKING_BATCH)?;
+        Self::require_operator(&env, &operator)?;
+        let mut results = Vec::new(&env);
+        for update in updates.iter() {
+            let result = Self::track(&env, &operator, update.mongo_id.clone(), update.tracking);
+            results.push_back(Self::batch_result(update.mongo_id, result));
         }
-        let mut state = Self::get_invoice_storage(&env);
+        Ok(results)
+    }
 
-        if let Some(mut invoice) = state.get(mongo_id.clone()) {
-            invoice.tracking = Track {
-                subject: subject,
-                status: status,
-                api_key_id: api_key_id,
-                event: event,
-                msg_id: msg_id,
-                to: to,
-            };
+    fn track(
+        env: &Env,
+        operator: &Address,
+        mongo_id: String,
+        tracking: Track,
+    ) -> Result<(), InvoiceError> {
+        if mongo_id.is_empty() || tracking.event.is_empty() {
+            log!(env, "Error: One or more input fields are empty");
+            return Err(InvoiceError::InvalidInput);
+        }
+        if let Some(mut invoice) = Self::get_invoice(env, &mongo_id) {
+            invoice.tracking = tracking;
 
-            state.set(mongo_id.clone(), invoice.clone());
-            Self::save_invoice_storage(&env, &state);
-            Self::save_history_storage(&env, invoice);
+            Self::commit(
+                env,
+                &invoice,
+                symbol_short!("tracking"),
+                Some(operator.clone()),
+            );
 
-            log!(&env, "Invoice {} tracking updated", mongo_id);
+            log!(env, "Invoice {} tracking updated", mongo_id);
             env.events()
                 .publish((symbol_short!("Track"),), mongo_id.clone());
-            return Ok(String::from_str(&env, "Invoice tracking updated"));
+            return Ok(());
         }
 
-        log!(&env, "Invoice {} not found", mongo_id);
+        log!(env, "Invoice {} not found", mongo_id);
         Err(InvoiceError::NotFound)
     }
 
     /// Retrieve invoice data
-    pub fn query_invoice(env: Env, mongo_id: String) -> Result<Invoice,InvoiceError> {
-        let state = Self::get_invoice_storage(&env);
-        if let Some(invoice) = state.get(mongo_id) {
+    pub fn query_invoice(env: Env, mongo_id: String) -> Result<Invoice, InvoiceError> {
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
             return Ok(invoice);
         }
-        return Err(InvoiceError::NotFound)
+        Err(InvoiceError::NotFound)
+    }
+
+    pub fn query_invoice_status(env: Env, mongo_id: String) -> Result<InvoiceStatus, InvoiceError> {
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            return Ok(invoice.status);
+        }
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Statuses an invoice in `from` may move to next. A call that fails with
+    /// `InvalidTransition` asked for a status missing from this list.
+    pub fn query_allowed_transitions(env: Env, from: InvoiceStatus) -> Vec<InvoiceStatus> {
+        let mut allowed = Vec::new(&env);
+        for (source, target) in INVOICE_TRANSITIONS.iter() {
+            if *source == from {
+                allowed.push_back(*target);
+            }
+        }
+        allowed
+    }
+
+    pub fn query_outstanding(env: Env, mongo_id: String) -> Result<i128, InvoiceError> {
+        if let Some(invoice) = Self::get_invoice(&env, &mongo_id) {
+            return Ok(Self::outstanding(&env, &invoice));
+        }
+        Err(InvoiceError::NotFound)
+    }
+
+    /// Late fees owed on an invoice as of now
+    pub fn query_late_fee(env: Env, mongo_id: String) -> Result<i128, InvoiceError> {
+        let invoice = Self::get_invoice(&env, &mongo_id).ok_or(InvoiceError::NotFound)?;
+        Ok(Self::late_fee_at(&env, &invoice, env.ledger().timestamp()))
     }
 
-    pub fn query_all_invoices(env: Env) -> Result<Vec<Invoice>, InvoiceError> {
-        let state = Self::get_invoice_storage(&env);
-        let mut invoices = Vec::new(&env);
+    pub fn query_late_fee_terms(env: Env, mongo_id: String) -> Result<LateFeeTerms, InvoiceError> {
+        Self::get_late_fee_terms(&env, &mongo_id).ok_or(InvoiceError::NotFound)
+    }
 
-        let keys_vec = state.keys(); 
+    pub fn query_payment_terms(env: Env, mongo_id: String) -> Result<PaymentTerms, InvoiceError> {
+        Self::get_payment_terms(&env, &mongo_id).ok_or(InvoiceError::NotFound)
+    }
 
-        let len = keys_vec.len();
+    pub fn query_dispute(env: Env, mongo_id: String) -> Result<Dispute, InvoiceError> {
+        Self::get_dispute(&env, &mongo_id).ok_or(InvoiceError::NotFound)
+    }
 
-        for i in 0..len {
-            let key = keys_vec.get(i).unwrap(); 
-            if let Some(invoice) = state.get(key) {
-                invoices.push_back(invoice);
-            }
+    pub fn query_is_arbiter(env: Env, arbiter: Address) -> bool {
+        Self::is_arbiter(&env, &arbiter)
+    }
+
+    /// Ids of the credit and debit notes issued against an invoice
+    pub fn query_notes(env: Env, mongo_id: String) -> Vec<String> {
+        Self::get_notes(&env, &mongo_id)
+    }
+
+    /// Invoice a credit or debit note was issued against
+    pub fn query_parent_invoice(env: Env, note_id: String) -> Result<String, InvoiceError> {
+        env.storage()
+            .persistent()
+            .get(&DataKey::ParentInvoice(note_id))
+            .ok_or(InvoiceError::NotFound)
+    }
+
+    /// Invoice amount after credit notes, debit notes and dispute settlements
+    pub fn query_adjusted_amount(env: Env, mongo_id: String) -> Result<i128, InvoiceError> {
+        let invoice = Self::get_invoice(&env, &mongo_id).ok_or(InvoiceError::NotFound)?;
+        Ok(Self::total(&invoice))
+    }
+
+    pub fn query_recurring_template(
+        env: Env,
+        template_id: u32,
+    ) -> Result<RecurringTemplate, InvoiceError> {
+        Self::get_template(&env, template_id).ok_or(InvoiceError::NotFound)
+    }
+
+    pub fn query_revision(env: Env, mongo_id: String) -> Result<u32, InvoiceError> {
+        if !Self::has_invoice(&env, &mongo_id) {
+            return Err(InvoiceError::NotFound);
         }
+        Ok(Self::get_revision(&env, &mongo_id))
+    }
+
+    pub fn query_escrow(env: Env, mongo_id: String) -> Result<Escrow, InvoiceError> {
+        Self::get_escrow(&env, &mongo_id).ok_or(InvoiceError::NotFound)
+    }
+
+    pub fn query_financier(env: Env, address: Address) -> Result<Financier, InvoiceError> {
+        Self::get_financier(&env, &address).ok_or(InvoiceError::FinancierNotFound)
+    }
 
-        if invoices.is_empty() {
+    pub fn query_bids(env: Env, mongo_id: String) -> Result<Vec<Bid>, InvoiceError> {
+        if !Self::has_invoice(&env, &mongo_id) {
             return Err(InvoiceError::NotFound);
         }
-        Ok(invoices)
+        Ok(Self::get_bids(&env, &mongo_id))
     }
 
-    pub fn query_invoice_history(env: Env, mongo_id: String) -> Result<Vec<Invoice>, InvoiceError> {
-        let history_state = Self::get_history_storage(&env);
+    pub fn query_payments(env: Env, mongo_id: String) -> Result<Vec<Payment>, InvoiceError> {
+        if !Self::has_invoice(&env, &mongo_id) {
+            return Err(InvoiceError::NotFound);
+        }
+        Ok(Self::get_payments(&env, &mongo_id))
+    }
 
-        if let Some(history) = history_state.get(mongo_id) {
-            return Ok(history);
+    pub fn query_installments(
+        env: Env,
+        mongo_id: String,
+    ) -> Result<Vec<Installment>, InvoiceError> {
+        if !Self::has_invoice(&env, &mongo_id) {
+            return Err(InvoiceError::NotFound);
         }
+        Ok(Self::get_installments(&env, &mongo_id))
+    }
 
-        return Err(InvoiceError::NotFound);
+    /// Check a page `limit` and cap it at `MAX_PAGE_SIZE`
+    fn page_limit(env: &Env, limit: u32) -> Result<u32, InvoiceError> {
+        if limit == 0 {
+            log!(env, "Error: Page limit must be positive");
+            return Err(InvoiceError::InvalidInput);
+        }
+        Ok(limit.min(MAX_PAGE_SIZE))
     }
 
-    pub fn query_by_txnhash(env: Env, txn_hash: String) -> Result<Vec<Invoice>, InvoiceError> {
-        let state = Self::get_invoice_storage(&env);
-        let mut matched_invoices = Vec::new(&env);
+    /// Up to `limit` ids from position `cursor` of the creation-order list, and the
+    /// position to continue from if the list goes on
+    fn invoice_ids(env: &Env, cursor: u32, limit: u32) -> (Vec<String>, Option<u32>) {
+        let count = Self::get_invoice_count(env);
+        let end = cursor.saturating_add(limit).min(count);
+        let mut ids = Vec::new(env);
+        for i in cursor..end {
+            if let Some(mongo_id) = Self::get_invoice_id(env, i) {
+                ids.push_back(mongo_id);
+            }
+        }
+        (ids, if end < count { Some(end) } else { None })
+    }
 
-        let keys_vec = state.keys(); 
-        let len = keys_vec.len();
+    /// Up to `limit` ids from position `cursor` of `index`, reading each page once
+    fn index_ids(
+        env: &Env,
+        index: &IndexKey,
+        cursor: u32,
+        limit: u32,
+    ) -> (Vec<String>, Option<u32>) {
+        let len = Self::get_index_len(env, index);
+        let end = cursor.saturating_add(limit).min(len);
+        let mut ids = Vec::new(env);
+        let mut page = Vec::new(env);
+        for pos in cursor..end {
+            if pos == cursor || pos % INDEX_PAGE_SIZE == 0 {
+                page = Self::get_index_page(env, index, pos / INDEX_PAGE_SIZE);
+            }
+            if let Some(mongo_id) = page.get(pos % INDEX_PAGE_SIZE) {
+                ids.push_back(mongo_id);
+            }
+        }
+        (ids, if end < len { Some(end) } else { None })
+    }
 
-        for i in 0..len {
-            let key = keys_vec.get(i).unwrap(); 
-            if let Some(invoice) = state.get(key) {
-                if invoice.txn_hash == txn_hash {
-                    matched_invoices.push_back(invoice);
+    /// Load the invoices behind a list of ids, keeping those that match. A filtered page
+    /// may hold fewer items than ids scanned, or none, while `next_cursor` is still set.
+    fn page(
+        env: &Env,
+        (ids, next_cursor): (Vec<String>, Option<u32>),
+        matches: impl Fn(&Invoice) -> bool,
+    ) -> InvoicePage {
+        let mut items = Vec::new(env);
+        for mongo_id in ids.iter() {
+            if let Some(invoice) = Self::get_invoice(env, &mongo_id) {
+                if matches(&invoice) {
+                    items.push_back(invoice);
                 }
             }
         }
+        InvoicePage { items, next_cursor }
+    }
+
+    /// A page of `index`, skipping stale entries
+    fn index_page(
+        env: &Env,
+        index: IndexKey,
+        cursor: u32,
+        limit: u32,
+        matches: impl Fn(&Invoice) -> bool,
+    ) -> Result<InvoicePage, InvoiceError> {
+        let limit = Self::page_limit(env, limit)?;
+        let ids = Self::index_ids(env, &index, cursor, limit);
+        Ok(Self::page(env, ids, |invoice| {
+            Self::in_index(env, &index, invoice) && matches(invoice)
+        }))
+    }
+
+    /// Every invoice in creation order. Deleting an invoice only hides it from the deleting
+    /// party's views, so none are left out here. `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn query_all_invoices(
+        env: Env,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        let limit = Self::page_limit(&env, limit)?;
+        Ok(Self::page(
+            &env,
+            Self::invoice_ids(&env, cursor, limit),
+            |_| true,
+        ))
+    }
+
+    fn in_range(value: u64, from: Option<u64>, to: Option<u64>) -> bool {
+        from.is_none_or(|from| value >= from) && to.is_none_or(|to| value <= to)
+    }
+
+    fn matches_filter(filter: &InvoiceFilter, invoice: &Invoice) -> bool {
+        let financed = !invoice.financing_details.is_empty();
+        (filter.statuses.is_empty() || filter.statuses.contains(invoice.status))
+            && filter.vendor.as_ref().is_none_or(|v| *v == invoice.vendor)
+            && filter.client.as_ref().is_none_or(|c| *c == invoice.client)
+            && filter
+                .currency
+                .as_ref()
+                .is_none_or(|c| *c == invoice.currency)
+            && Self::in_range(
+                invoice.creation_date,
+                filter.created_from,
+                filter.created_to,
+            )
+            && Self::in_range(invoice.due_date, filter.due_from, filter.due_to)
+            && filter
+                .overdue_at
+                .is_none_or(|as_of| Self::is_overdue(invoice, as_of))
+            && filter.min_amount.is_none_or(|min| invoice.net_amt >= min)
+            && filter.max_amount.is_none_or(|max| invoice.net_amt <= max)
+            && filter.financed.is_none_or(|f| f == financed)
+            && filter
+                .sent_deleted
+                .is_none_or(|d| d == invoice.sent_invoice_deleted)
+            && filter
+                .received_deleted
+                .is_none_or(|d| d == invoice.received_invoice_deleted)
+    }
+
+    /// Search invoices by `filter`. The scan walks the client index, the vendor's status or
+    /// vendor index when the filter pins them, and every invoice otherwise; `cursor` is a
+    /// position in that list, so pages may come back sparse. `limit` is capped at
+    /// `MAX_PAGE_SIZE`. A pinned vendor or client leaves out invoices that party has
+    /// deleted unless `sent_deleted` or `received_deleted` asks for them; with neither
+    /// pinned, deleted invoices are only left out on request.
+    pub fn query_invoices(
+        env: Env,
+        filter: InvoiceFilter,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        let mut filter = filter;
+        if filter.vendor.is_some() && filter.sent_deleted.is_none() {
+            filter.sent_deleted = Some(false);
+        }
+        if filter.client.is_some() && filter.received_deleted.is_none() {
+            filter.received_deleted = Some(false);
+        }
+        let index = match (&filter.client, &filter.vendor) {
+            (Some(client), _) => Some(IndexKey::Client(client.clone())),
+            (None, Some(vendor)) if filter.statuses.len() == 1 => Some(IndexKey::Status(
+                vendor.clone(),
+                filter.statuses.get(0).unwrap(),
+            )),
+            (None, Some(vendor)) => Some(IndexKey::Vendor(vendor.clone())),
+            (None, None) => None,
+        };
+
+        let matches = |invoice: &Invoice| Self::matches_filter(&filter, invoice);
+        match index {
+            Some(index) => Self::index_page(&env, index, cursor, limit, matches),
+            None => {
+                let limit = Self::page_limit(&env, limit)?;
+                Ok(Self::page(
+                    &env,
+                    Self::invoice_ids(&env, cursor, limit),
+                    matches,
+                ))
+            }
+        }
+    }
 
-        if matched_invoices.is_empty() {
+    /// Rebuild an invoice as it stood after history event `index`, replaying at most
+    /// `HISTORY_SNAPSHOT_INTERVAL - 1` events on top of the latest snapshot before it
+    pub fn reconstruct_invoice_at(
+        env: Env,
+        mongo_id: String,
+        index: u32,
+    ) -> Result<Invoice, InvoiceError> {
+        if index >= Self::get_history_count(&env, &mongo_id) {
             return Err(InvoiceError::NotFound);
         }
+        Self::history_state(&env, &mongo_id, index).ok_or(InvoiceError::NotFound)
+    }
 
-        return Ok(matched_invoices);
+    /// Open invoices past their due date at `as_of`, scanning every invoice.
+    /// `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn query_overdue(
+        env: Env,
+        as_of: u64,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        let limit = Self::page_limit(&env, limit)?;
+        Ok(Self::page(
+            &env,
+            Self::invoice_ids(&env, cursor, limit),
+            |invoice| Self::is_overdue(invoice, as_of),
+        ))
     }
-    
 
-    pub fn query_by_vendor_emailhash(
+    /// Age a vendor's open invoices in `currency` at `as_of`, late fees included.
+    /// Walks the vendor index; `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn query_aging(
         env: Env,
-        email_hash: String,
-    ) -> Result<Vec<Invoice>, InvoiceError> {
-        let state = Self::get_invoice_storage(&env);
-        let mut matched_invoices = Vec::new(&env);
-
-        let keys_vec = state.keys(); 
-        let len = keys_vec.len();
-
-        for i in 0..len {
-            let key = keys_vec.get(i).unwrap(); 
-            if let Some(invoice) = state.get(key) {
-                if invoice.vendor_email_hash == email_hash {
-                    matched_invoices.push_back(invoice);
-                }
-            }
+        vendor: Address,
+        currency: String,
+        as_of: u64,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<AgingReport, InvoiceError> {
+        let page = Self::index_page(&env, IndexKey::Vendor(vendor), cursor, limit, |invoice| {
+            Self::is_open(invoice) && invoice.currency == currency
+        })?;
+
+        let mut report = AgingReport {
+            current: 0,
+            days_1_30: 0,
+            days_31_60: 0,
+            days_61_90: 0,
+            days_over_90: 0,
+            next_cursor: page.next_cursor,
+        };
+        for invoice in page.items.iter() {
+            let balance = Self::outstanding_at(&env, &invoice, as_of);
+            let days_late = as_of
+                .saturating_sub(invoice.due_date)
+                .div_ceil(DAY_IN_SECONDS);
+            let bucket = match days_late {
+                0 => &mut report.current,
+                1..=30 => &mut report.days_1_30,
+                31..=60 => &mut report.days_31_60,
+                61..=90 => &mut report.days_61_90,
+                _ => &mut report.days_over_90,
+            };
+            *bucket += balance;
         }
+        Ok(report)
+    }
 
-        if matched_invoices.is_empty() {
+    /// Up to `limit` history events of an invoice from event `cursor`. `limit` is capped at
+    /// `MAX_PAGE_SIZE`.
+    pub fn query_history_events(
+        env: Env,
+        mongo_id: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<HistoryPage, InvoiceError> {
+        let limit = Self::page_limit(&env, limit)?;
+        let count = Self::get_history_count(&env, &mongo_id);
+        if count == 0 {
             return Err(InvoiceError::NotFound);
         }
+        let end = cursor.saturating_add(limit).min(count);
+        let mut items = Vec::new(&env);
+        for i in cursor..end {
+            if let Some(entry) = Self::get_history_entry(&env, &mongo_id, i) {
+                items.push_back(entry.event);
+            }
+        }
+        Ok(HistoryPage {
+            items,
+            next_cursor: if end < count { Some(end) } else { None },
+        })
+    }
 
-        return Ok(matched_invoices);
+    /// The invoice as it stood after each of up to `limit` history events from `cursor`.
+    /// `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn query_invoice_history(
+        env: Env,
+        mongo_id: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        let limit = Self::page_limit(&env, limit)?;
+        let count = Self::get_history_count(&env, &mongo_id);
+        if count == 0 {
+            return Err(InvoiceError::NotFound);
+        }
+        let end = cursor.saturating_add(limit).min(count);
+        Ok(InvoicePage {
+            items: Self::get_history(&env, &mongo_id, cursor, limit),
+            next_cursor: if end < count { Some(end) } else { None },
+        })
     }
 
-    pub fn query_by_vendor_mobilehash(
+    /// Recompute up to `limit` links of an invoice's history chain from entry `cursor`,
+    /// chaining from the stored digest of the entry before it. Reports the index of the first
+    /// entry whose digest or link to its predecessor does not match, and the cursor for the
+    /// next call while the chain goes on. `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn verify_history(
         env: Env,
-        mobile_hash: String,
-    ) -> Result<Vec<Invoice>, InvoiceError> {
-        let state = Self::get_invoice_storage(&env);
-        let mut matched_invoices = Vec::new(&env);
-
-        let keys_vec = state.keys(); 
-        let len = keys_vec.len();
-
-        for i in 0..len {
-            let key = keys_vec.get(i).unwrap(); 
-            if let Some(invoice) = state.get(key) {
-                if invoice.vendor_mobile_hash == mobile_hash {
-                    matched_invoices.push_back(invoice);
+        mongo_id: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<HistoryCheck, InvoiceError> {
+        let limit = Self::page_limit(&env, limit)?;
+        let count = Self::get_history_count(&env, &mongo_id);
+        if count == 0 || cursor >= count {
+            return Err(InvoiceError::NotFound);
+        }
+
+        let mut prev_digest = match cursor {
+            0 => BytesN::from_array(&env, &[0; 32]),
+            _ => match Self::get_history_entry(&env, &mongo_id, cursor - 1) {
+                Some(entry) => entry.digest,
+                None => {
+                    return Ok(HistoryCheck {
+                        mismatch: Some(cursor - 1),
+                        next_cursor: None,
+                    })
+                }
+            },
+        };
+        let end = cursor.saturating_add(limit).min(count);
+        for i in cursor..end {
+            let entry = match Self::get_history_entry(&env, &mongo_id, i) {
+                Some(entry) => entry,
+                None => {
+                    return Ok(HistoryCheck {
+                        mismatch: Some(i),
+                        next_cursor: None,
+                    })
                 }
+            };
+            if entry.prev_digest != prev_digest
+                || entry.digest != Self::history_digest(&env, &entry.event, &prev_digest)
+            {
+                return Ok(HistoryCheck {
+                    mismatch: Some(i),
+                    next_cursor: None,
+                });
             }
+            prev_digest = entry.digest;
         }
+        Ok(HistoryCheck {
+            mismatch: None,
+            next_cursor: if end < count { Some(end) } else { None },
+        })
+    }
 
-        if matched_invoices.is_empty() {
+    /// Digest of the latest history entry, for anchoring the chain off-chain
+    pub fn query_history_head(env: Env, mongo_id: String) -> Result<BytesN<32>, InvoiceError> {
+        let count = Self::get_history_count(&env, &mongo_id);
+        if count == 0 {
             return Err(InvoiceError::NotFound);
         }
+        Self::get_history_entry(&env, &mongo_id, count - 1)
+            .map(|entry| entry.digest)
+            .ok_or(InvoiceError::NotFound)
+    }
+
+    /// Invoices whose current or any earlier txn hash is `txn_hash`, deleted ones included
+    pub fn query_by_txnhash(
+        env: Env,
+        txn_hash: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(&env, IndexKey::TxnHash(txn_hash), cursor, limit, |_| true)
+    }
+
+    /// The vendor-side lookups below leave out invoices the vendor has deleted
+    pub fn query_by_vendor_emailhash(
+        env: Env,
+        email_hash: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(
+            &env,
+            IndexKey::VendorEmailHash(email_hash),
+            cursor,
+            limit,
+            |invoice| !invoice.sent_invoice_deleted,
+        )
+    }
+
+    pub fn query_by_vendor_mobilehash(
+        env: Env,
+        mobile_hash: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(
+            &env,
+            IndexKey::VendorMobileHash(mobile_hash),
+            cursor,
+            limit,
+            |invoice| !invoice.sent_invoice_deleted,
+        )
+    }
+
+    pub fn query_by_vendor_id(
+        env: Env,
+        vendor_id: String,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(
+            &env,
+            IndexKey::VendorId(vendor_id),
+            cursor,
+            limit,
+            |invoice| !invoice.sent_invoice_deleted,
+        )
+    }
+
+    /// Leaves out invoices the client has deleted
+    pub fn query_by_client(
+        env: Env,
+        client: Address,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(&env, IndexKey::Client(client), cursor, limit, |invoice| {
+            !invoice.received_invoice_deleted
+        })
+    }
+
+    /// A vendor's invoices in `status`, leaving out those the vendor has deleted
+    pub fn query_by_status(
+        env: Env,
+        vendor: Address,
+        status: InvoiceStatus,
+        cursor: u32,
+        limit: u32,
+    ) -> Result<InvoicePage, InvoiceError> {
+        Self::index_page(
+            &env,
+            IndexKey::Status(vendor, status),
+            cursor,
+            limit,
+            |invoice| !invoice.sent_invoice_deleted,
+        )
+    }
+
+    pub fn query_currency_decimals(env: Env, currency: String) -> Result<u32, InvoiceError> {
+        Self::get_currency_decimals(&env, &currency).ok_or(InvoiceError::UnsupportedCurrency)
+    }
+
+    pub fn query_admin(env: Env) -> Result<Address, InvoiceError> {
+        Self::get_admin(&env)
+    }
 
-        return Ok(matched_invoices);
+    pub fn query_is_operator(env: Env, operator: Address) -> bool {
+        Self::is_operator_stored(&env, &operator)
     }
 
     pub fn query_total_invoice_count(env: Env) -> u32 {
-        let state = Self::get_invoice_storage(&env);
-        state.keys().len() as u32
+        Self::get_invoice_count(&env)
     }
 }
 
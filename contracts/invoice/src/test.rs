@@ -1,21 +1,2403 @@
-// #![cfg(test)]
-
-// use super::*;
-// use soroban_sdk::{vec, Env, String};
-
-// #[test]
-// fn test() {
-//     let env = Env::default();
-//     let contract_id = env.register(Contract, ());
-//     let client = ContractClient::new(&env, &contract_id);
-
-//     let words = client.hello(&String::from_str(&env, "Dev"));
-//     assert_eq!(
-//         words,
-//         vec![
-//             &env,
-//             String::from_str(&env, "Hello"),
-//             String::from_str(&env, "Dev"),
-//         ]
-//     );
-// }
+#![cfg(test)]
+extern crate std;
+
+use super::*;
+use soroban_sdk::{
+    testutils::{Address as _, Ledger, MockAuth, MockAuthInvoke},
+    token::{StellarAssetClient, TokenClient},
+    vec, Address, Bytes, BytesN, Env, Map, String, Vec,
+};
+
+const CREATED: u64 = 1_700_000_000;
+const DUE: u64 = CREATED + 30 * DAY_IN_SECONDS;
+
+fn text(env: &Env, value: &str) -> String {
+    String::from_str(env, value)
+}
+
+fn setup(env: &Env) -> (InvoiceContractClient<'_>, Address) {
+    env.mock_all_auths();
+    env.ledger().set_timestamp(CREATED);
+    let admin = Address::generate(env);
+    let contract_id = env.register(InvoiceContract, (admin.clone(),));
+    (InvoiceContractClient::new(env, &contract_id), admin)
+}
+
+/// Register USD with a Stellar Asset Contract and return the token
+fn setup_token(
+    env: &Env,
+    contract: &InvoiceContractClient,
+    admin: &Address,
+) -> TokenClient<'static> {
+    let sac = env.register_stellar_asset_contract_v2(admin.clone());
+    contract.set_currency(&text(env, "USD"), &7);
+    contract.set_currency_token(&text(env, "USD"), &sac.address());
+    TokenClient::new(env, &sac.address())
+}
+
+fn mint(env: &Env, token: &TokenClient, to: &Address, amount: i128) {
+    StellarAssetClient::new(env, &token.address).mint(to, &amount);
+}
+
+/// A public USD invoice for 99: two units at 50, 10 off, 10% tax
+fn invoice(env: &Env, mongo_id: &str, vendor: &Address, client: &Address) -> Invoice {
+    let empty = text(env, "");
+    Invoice {
+        inv_type: empty.clone(),
+        vendor: vendor.clone(),
+        client: client.clone(),
+        vendor_id: text(env, "vendor-1"),
+        mongo_id: text(env, mongo_id),
+        creation_date: CREATED,
+        vendor_email: text(env, "vendor@example.com"),
+        action: text(env, "create"),
+        status: InvoiceStatus::Issued,
+        financing_details: Vec::new(env),
+        vendor_email_hash: text(env, "email-hash"),
+        vendor_mobile_hash: text(env, "mobile-hash"),
+        vendor_mobile: text(env, "+100"),
+        client_fname: text(env, "Ada"),
+        client_lname: text(env, "Lovelace"),
+        vendor_name: text(env, "Vendor"),
+        client_email: text(env, "client@example.com"),
+        client_mobile: text(env, "+200"),
+        currency: text(env, "USD"),
+        fund_reception: vendor.clone(),
+        lines: vec![
+            env,
+            LineItem {
+                description: text(env, "Widget"),
+                quantity: 2,
+                unit_price: 50,
+                discount: 10,
+                tax_rate: 1000,
+                tax_amount: 9,
+            },
+        ],
+        subtotal: 90,
+        tax_total: 9,
+        net_amt: 99,
+        amount_paid: 0,
+        adjustment: 0,
+        late_fee: 0,
+        discount: 0,
+        escrow: false,
+        decimals: 0,
+        sent_invoice_deleted: false,
+        received_invoice_deleted: false,
+        timestamp: 0,
+        previous_invoice_hash: empty.clone(),
+        txn_hash: text(env, &std::format!("{}-create", mongo_id)),
+        due_date: DUE,
+        deleted_comments: empty.clone(),
+        tracking: Track {
+            subject: empty.clone(),
+            status: empty.clone(),
+            msg_id: empty.clone(),
+            api_key_id: empty.clone(),
+            event: empty.clone(),
+            to: empty,
+        },
+        privacy: Privacy::Public,
+    }
+}
+
+fn create_acked(env: &Env, contract: &InvoiceContractClient, invoice: &Invoice) {
+    contract.create_invoice(invoice);
+    contract.ack_invoice(
+        &invoice.mongo_id,
+        &0,
+        &text(env, "ack"),
+        &text(env, "ack-hash"),
+    );
+}
+
+fn pay(env: &Env, contract: &InvoiceContractClient, mongo_id: &str, payer: &Address, amount: i128) {
+    contract.pay_invoice(
+        &text(env, mongo_id),
+        payer,
+        &amount,
+        &text(env, "ref"),
+        &text(env, "pay"),
+        &text(env, &std::format!("{}-pay-{}", mongo_id, amount)),
+    );
+}
+
+#[test]
+fn pay_invoice_transfers_to_vendor() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+
+    pay(&env, &contract, "1", &client, 99);
+
+    assert_eq!(token.balance(&vendor), 99);
+    assert_eq!(token.balance(&client), 901);
+    let id = text(&env, "1");
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Paid);
+    assert_eq!(contract.query_outstanding(&id), 0);
+    assert_eq!(contract.query_payments(&id).get(0).unwrap().payer, client);
+}
+
+#[test]
+fn pay_invoice_requires_the_client() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, other) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &other, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+
+    let id = text(&env, "1");
+    let result = contract.try_pay_invoice(
+        &id,
+        &other,
+        &99,
+        &text(&env, "ref"),
+        &text(&env, "pay"),
+        &text(&env, "pay-hash"),
+    );
+    assert_eq!(result, Err(Ok(InvoiceError::Unauthorized)));
+    assert_eq!(
+        contract.try_paid_invoice(&id, &text(&env, "pay"), &text(&env, "pay-hash")),
+        Err(Ok(InvoiceError::TokenSettlementRequired))
+    );
+    assert_eq!(token.balance(&other), 1_000);
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::Acknowledged
+    );
+}
+
+#[test]
+fn partial_payments_fill_installments_in_order() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    contract.set_installments(
+        &id,
+        &vec![
+            &env,
+            Installment {
+                due_date: CREATED + DAY_IN_SECONDS,
+                amount: 50,
+                paid: 0,
+            },
+            Installment {
+                due_date: DUE,
+                amount: 49,
+                paid: 0,
+            },
+        ],
+    );
+
+    pay(&env, &contract, "1", &client, 60);
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::PartiallyPaid
+    );
+    let schedule = contract.query_installments(&id);
+    assert_eq!(schedule.get(0).unwrap().paid, 50);
+    assert_eq!(schedule.get(1).unwrap().paid, 10);
+
+    let overpay = contract.try_pay_invoice(
+        &id,
+        &client,
+        &40,
+        &text(&env, "ref"),
+        &text(&env, "pay"),
+        &text(&env, "overpay"),
+    );
+    assert_eq!(overpay, Err(Ok(InvoiceError::InvalidAmount)));
+
+    pay(&env, &contract, "1", &client, 39);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Paid);
+    assert_eq!(contract.query_payments(&id).len(), 2);
+    assert_eq!(token.balance(&vendor), 99);
+}
+
+#[test]
+fn aging_buckets_by_whole_days_past_due() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    let as_of = DUE + 91 * DAY_IN_SECONDS;
+    for days_late in [0, 1, 30, 31, 90, 91] {
+        let id = std::format!("late-{}", days_late);
+        create_acked(
+            &env,
+            &contract,
+            &Invoice {
+                due_date: as_of - days_late * DAY_IN_SECONDS,
+                ..invoice(&env, &id, &vendor, &client)
+            },
+        );
+    }
+    // A second past due still counts as a day late
+    create_acked(
+        &env,
+        &contract,
+        &Invoice {
+            due_date: as_of - 1,
+            ..invoice(&env, "late-1s", &vendor, &client)
+        },
+    );
+    create_acked(&env, &contract, &invoice(&env, "paid", &vendor, &client));
+    pay(&env, &contract, "paid", &client, 99);
+
+    let report = contract.query_aging(&vendor, &text(&env, "USD"), &as_of, &0, &MAX_PAGE_SIZE);
+    assert_eq!(report.current, 99);
+    assert_eq!(report.days_1_30, 3 * 99);
+    assert_eq!(report.days_31_60, 99);
+    assert_eq!(report.days_61_90, 99);
+    assert_eq!(report.days_over_90, 99);
+    assert_eq!(report.next_cursor, None);
+    let other = contract.query_aging(&vendor, &text(&env, "EUR"), &as_of, &0, &MAX_PAGE_SIZE);
+    assert_eq!(other.current + other.days_1_30 + other.days_over_90, 0);
+
+    let overdue = collect_ids(|cursor| contract.query_overdue(&as_of, &cursor, &MAX_PAGE_SIZE));
+    assert_eq!(
+        overdue,
+        ["late-1", "late-30", "late-31", "late-90", "late-91", "late-1s"]
+    );
+    let paged = contract.query_overdue(&as_of, &0, &2);
+    assert_eq!(paged.next_cursor, Some(2));
+    assert!(matches!(
+        contract.try_query_overdue(&as_of, &0, &0),
+        Err(Ok(InvoiceError::InvalidInput))
+    ));
+}
+
+#[test]
+fn late_fees_accrue_by_whole_periods_until_settled() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    let week = 7 * DAY_IN_SECONDS;
+    let flat = LateFeeTerms {
+        kind: LateFeeKind::Flat,
+        amount: 3,
+        period: week,
+    };
+    let percentage = LateFeeTerms {
+        kind: LateFeeKind::Percentage,
+        amount: 1_000,
+        period: 30 * DAY_IN_SECONDS,
+    };
+    for (id, terms) in [("flat", &flat), ("rate", &percentage)] {
+        contract.create_invoice(&invoice(&env, id, &vendor, &client));
+        let id = text(&env, id);
+        contract.set_late_fee(&id, terms);
+        contract.ack_invoice(
+            &id,
+            &contract.query_revision(&id),
+            &text(&env, "ack"),
+            &text(&env, "ack-hash"),
+        );
+        assert_eq!(
+            contract.try_set_late_fee(&id, terms),
+            Err(Ok(InvoiceError::InvalidTransition))
+        );
+    }
+    let (flat_id, rate_id) = (text(&env, "flat"), text(&env, "rate"));
+    assert_eq!(contract.query_late_fee_terms(&flat_id).amount, 3);
+    assert_eq!(
+        contract.try_set_late_fee(&text(&env, "missing"), &flat),
+        Err(Ok(InvoiceError::NotFound))
+    );
+    assert_eq!(
+        contract.try_set_late_fee(
+            &flat_id,
+            &LateFeeTerms {
+                period: 0,
+                ..flat.clone()
+            }
+        ),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+    assert_eq!(
+        contract.try_set_late_fee(
+            &rate_id,
+            &LateFeeTerms {
+                amount: 10_001,
+                ..percentage.clone()
+            }
+        ),
+        Err(Ok(InvoiceError::InvalidAmount))
+    );
+
+    let at = |offset: u64| env.ledger().set_timestamp(DUE + offset);
+    at(0);
+    assert_eq!(contract.query_late_fee(&flat_id), 0);
+    at(week - 1);
+    assert_eq!(contract.query_late_fee(&flat_id), 0);
+    at(week);
+    assert_eq!(contract.query_late_fee(&flat_id), 3);
+    at(3 * week - 1);
+    assert_eq!(contract.query_late_fee(&flat_id), 6);
+    // 10% of 99 is 9 per 30 days
+    at(60 * DAY_IN_SECONDS - 1);
+    assert_eq!(contract.query_late_fee(&rate_id), 9);
+    at(60 * DAY_IN_SECONDS);
+    assert_eq!(contract.query_late_fee(&rate_id), 18);
+    assert_eq!(contract.query_outstanding(&rate_id), 99 + 18);
+
+    // Settling records the fee owed, and nothing accrues afterwards
+    pay(&env, &contract, "rate", &client, 99 + 18);
+    assert_eq!(contract.query_invoice_status(&rate_id), InvoiceStatus::Paid);
+    assert_eq!(contract.query_invoice(&rate_id).late_fee, 18);
+    at(365 * DAY_IN_SECONDS);
+    assert_eq!(contract.query_late_fee(&rate_id), 18);
+    assert_eq!(contract.query_outstanding(&rate_id), 0);
+    assert_eq!(contract.query_late_fee(&flat_id), 3 * 52);
+}
+
+#[test]
+fn payment_terms_set_the_due_date() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    let terms = PaymentTerms {
+        discount_rate: 200,
+        discount_period: 10 * DAY_IN_SECONDS,
+        net_period: 45 * DAY_IN_SECONDS,
+    };
+    for invalid in [
+        PaymentTerms {
+            net_period: 0,
+            discount_period: 0,
+            ..terms.clone()
+        },
+        PaymentTerms {
+            discount_period: 46 * DAY_IN_SECONDS,
+            ..terms.clone()
+        },
+        PaymentTerms {
+            discount_rate: 10_000,
+            ..terms.clone()
+        },
+    ] {
+        assert_eq!(
+            contract.try_set_payment_terms(&id, &invalid),
+            Err(Ok(InvoiceError::InvalidInput))
+        );
+    }
+
+    contract.set_payment_terms(&id, &terms);
+    assert_eq!(authorizers(&env), std::vec![vendor.clone()]);
+    assert_eq!(contract.query_payment_terms(&id).discount_rate, 200);
+    assert_eq!(
+        contract.query_invoice(&id).due_date,
+        CREATED + 45 * DAY_IN_SECONDS
+    );
+    let events = contract.query_history_events(&id, &0, &MAX_PAGE_SIZE).items;
+    assert_eq!(events.last().unwrap().action, symbol_short!("terms"));
+
+    contract.ack_invoice(
+        &id,
+        &contract.query_revision(&id),
+        &text(&env, "ack"),
+        &text(&env, "ack-hash"),
+    );
+    assert_eq!(
+        contract.try_set_payment_terms(&id, &terms),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert!(matches!(
+        contract.try_query_payment_terms(&text(&env, "2")),
+        Err(Ok(InvoiceError::NotFound))
+    ));
+}
+
+#[test]
+fn early_payment_discount_needs_settlement_inside_the_window() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    // 10/10 net 30: 9 off the 99 when settled within ten days
+    let terms = PaymentTerms {
+        discount_rate: 1_000,
+        discount_period: 10 * DAY_IN_SECONDS,
+        net_period: 30 * DAY_IN_SECONDS,
+    };
+    for id in ["inside", "after", "split"] {
+        contract.create_invoice(&invoice(&env, id, &vendor, &client));
+        let id = text(&env, id);
+        contract.set_payment_terms(&id, &terms);
+        contract.ack_invoice(
+            &id,
+            &contract.query_revision(&id),
+            &text(&env, "ack"),
+            &text(&env, "ack-hash"),
+        );
+    }
+
+    env.ledger().set_timestamp(CREATED + 5 * DAY_IN_SECONDS);
+    pay(&env, &contract, "split", &client, 50);
+    assert_eq!(contract.query_outstanding(&text(&env, "split")), 40);
+
+    // The last second of the window still earns the discount
+    env.ledger().set_timestamp(CREATED + 10 * DAY_IN_SECONDS);
+    let inside = text(&env, "inside");
+    assert_eq!(contract.query_outstanding(&inside), 90);
+    pay(&env, &contract, "inside", &client, 90);
+    let settled = contract.query_invoice(&inside);
+    assert_eq!(settled.status, InvoiceStatus::Paid);
+    assert_eq!((settled.amount_paid, settled.discount), (90, 9));
+    assert_eq!(contract.query_outstanding(&inside), 0);
+
+    // A day later the full amount is due, even after a partial payment inside the window
+    env.ledger().set_timestamp(CREATED + 11 * DAY_IN_SECONDS);
+    let after = text(&env, "after");
+    assert_eq!(contract.query_outstanding(&after), 99);
+    pay(&env, &contract, "after", &client, 90);
+    assert_eq!(
+        contract.query_invoice_status(&after),
+        InvoiceStatus::PartiallyPaid
+    );
+    let split = text(&env, "split");
+    assert_eq!(contract.query_outstanding(&split), 49);
+    pay(&env, &contract, "split", &client, 49);
+    let settled = contract.query_invoice(&split);
+    assert_eq!(settled.status, InvoiceStatus::Paid);
+    assert_eq!((settled.amount_paid, settled.discount), (99, 0));
+    assert_eq!(token.balance(&vendor), 90 + 90 + 99);
+}
+
+#[test]
+fn escrow_is_released_on_confirmation() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    let mut escrowed = invoice(&env, "1", &vendor, &client);
+    escrowed.escrow = true;
+    create_acked(&env, &contract, &escrowed);
+
+    pay(&env, &contract, "1", &client, 99);
+    assert_eq!(token.balance(&contract.address), 99);
+    assert_eq!(token.balance(&vendor), 0);
+    let id = text(&env, "1");
+    assert_eq!(contract.query_escrow(&id).balance, 99);
+
+    contract.payment_confirmation_invoice(&id, &text(&env, "confirm"), &text(&env, "confirm-hash"));
+    assert_eq!(token.balance(&contract.address), 0);
+    assert_eq!(token.balance(&vendor), 99);
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::PaymentConfirmed
+    );
+    assert!(contract.try_query_escrow(&id).is_err());
+}
+
+#[test]
+fn escrow_is_refunded_after_timeout() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    let mut escrowed = invoice(&env, "1", &vendor, &client);
+    escrowed.escrow = true;
+    create_acked(&env, &contract, &escrowed);
+    pay(&env, &contract, "1", &client, 99);
+
+    let id = text(&env, "1");
+    let (action, hash) = (text(&env, "refund"), text(&env, "refund-hash"));
+    assert_eq!(
+        contract.try_refund_escrow(&id, &action, &hash),
+        Err(Ok(InvoiceError::EscrowLocked))
+    );
+
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp += DEFAULT_ESCROW_TIMEOUT);
+    contract.refund_escrow(&id, &action, &hash);
+    assert_eq!(token.balance(&client), 1_000);
+    assert_eq!(token.balance(&contract.address), 0);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Refunded);
+    assert_eq!(contract.query_invoice(&id).amount_paid, 0);
+}
+
+#[test]
+fn accepted_bid_routes_payments_to_financier() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let (loser, winner) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    mint(&env, &token, &loser, 1_000);
+    mint(&env, &token, &winner, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&loser, &text(&env, "Loser"), &kyc, &1_000, &1_000);
+    contract.register_financier(&winner, &text(&env, "Winner"), &kyc, &1_000, &1_000);
+    let id = text(&env, "1");
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    assert_eq!(
+        contract.try_list_for_financing(&id),
+        Err(Ok(InvoiceError::InvoiceNotAcknowledged))
+    );
+    contract.ack_invoice(&id, &0, &text(&env, "ack"), &text(&env, "ack-hash"));
+    contract.list_for_financing(&id);
+
+    let expiry = CREATED + DAY_IN_SECONDS;
+    contract.submit_bid(&loser, &id, &8_000, &500, &expiry);
+    let bid_id = contract.submit_bid(&winner, &id, &9_000, &500, &expiry);
+    assert_eq!(token.balance(&contract.address), 79 + 89);
+
+    contract.accept_bid(
+        &id,
+        &bid_id,
+        &text(&env, "accept"),
+        &text(&env, "accept-hash"),
+    );
+    assert_eq!(token.balance(&contract.address), 0);
+    assert_eq!(token.balance(&loser), 1_000);
+    assert_eq!(token.balance(&vendor), 89);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Financed);
+    assert_eq!(contract.query_financier(&winner).exposure, 89);
+
+    // The financier collects its advance and 5% fee (4, rounded down); the rest goes to the vendor
+    pay(&env, &contract, "1", &client, 99);
+    assert_eq!(token.balance(&winner), 1_000 - 89 + 93);
+    assert_eq!(token.balance(&vendor), 89 + 6);
+    assert_eq!(contract.query_financier(&winner).exposure, 0);
+    let record = contract
+        .query_invoice(&id)
+        .financing_details
+        .get(0)
+        .unwrap();
+    assert_eq!((record.financier, record.repaid), (winner, 93));
+}
+
+#[test]
+fn bids_can_be_withdrawn_and_expire() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let (first, second) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &first, 1_000);
+    mint(&env, &token, &second, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&first, &text(&env, "First"), &kyc, &1_000, &1_000);
+    contract.register_financier(&second, &text(&env, "Second"), &kyc, &1_000, &1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    contract.list_for_financing(&id);
+
+    assert_eq!(
+        contract.try_submit_bid(&first, &id, &8_000, &500, &CREATED),
+        Err(Ok(InvoiceError::BidExpired))
+    );
+    let expiry = CREATED + DAY_IN_SECONDS;
+    let withdrawn = contract.submit_bid(&first, &id, &8_000, &500, &expiry);
+    let expiring = contract.submit_bid(&second, &id, &9_000, &500, &expiry);
+    assert_eq!(token.balance(&first), 1_000 - 79);
+
+    assert_eq!(
+        contract.try_withdraw_bid(&second, &id, &withdrawn),
+        Err(Ok(InvoiceError::NotFound))
+    );
+    contract.withdraw_bid(&first, &id, &withdrawn);
+    assert_eq!(token.balance(&first), 1_000);
+    assert_eq!(contract.query_bids(&id).len(), 1);
+    assert_eq!(
+        contract.try_accept_bid(&id, &withdrawn, &text(&env, "accept"), &text(&env, "hash")),
+        Err(Ok(InvoiceError::NotFound))
+    );
+
+    env.ledger().set_timestamp(expiry);
+    assert_eq!(
+        contract.try_accept_bid(&id, &expiring, &text(&env, "accept"), &text(&env, "hash")),
+        Err(Ok(InvoiceError::BidExpired))
+    );
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::Acknowledged
+    );
+    // An expired bid's advance stays claimable by its financier
+    contract.withdraw_bid(&second, &id, &expiring);
+    assert_eq!(token.balance(&second), 1_000);
+    assert_eq!(token.balance(&contract.address), 0);
+}
+
+#[test]
+fn direct_financing_pays_vendor_and_routes_payments() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    mint(&env, &token, &financier, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+
+    let id = text(&env, "1");
+    contract.finance_invoice(
+        &id,
+        &financier,
+        &text(&env, "fin-1"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash"),
+    );
+    assert_eq!(token.balance(&vendor), 80);
+    assert_eq!(contract.query_financier(&financier).exposure, 80);
+
+    pay(&env, &contract, "1", &client, 50);
+    assert_eq!(token.balance(&financier), 1_000 - 80 + 50);
+    assert_eq!(contract.query_financier(&financier).exposure, 30);
+
+    // 35 more covers the advance and fee; the last 14 is the vendor's
+    pay(&env, &contract, "1", &client, 49);
+    assert_eq!(token.balance(&financier), 1_000 + 5);
+    assert_eq!(token.balance(&vendor), 80 + 14);
+    assert_eq!(contract.query_financier(&financier).exposure, 0);
+}
+
+#[test]
+fn refunding_a_financed_invoice_writes_off_the_advance() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    mint(&env, &token, &financier, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &80);
+    let mut escrowed = invoice(&env, "1", &vendor, &client);
+    escrowed.escrow = true;
+    create_acked(&env, &contract, &escrowed);
+
+    let id = text(&env, "1");
+    contract.finance_invoice(
+        &id,
+        &financier,
+        &text(&env, "fin-1"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash"),
+    );
+    pay(&env, &contract, "1", &client, 50);
+    assert_eq!(contract.query_financier(&financier).exposure, 80);
+
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp += DEFAULT_ESCROW_TIMEOUT);
+    contract.refund_escrow(&id, &text(&env, "refund"), &text(&env, "refund-hash"));
+    assert_eq!(token.balance(&client), 1_000);
+    assert_eq!(contract.query_financier(&financier).exposure, 0);
+    let record = contract
+        .query_invoice(&id)
+        .financing_details
+        .get(0)
+        .unwrap();
+    assert_eq!((record.repaid, record.written_off), (0, 80));
+
+    // The vendor limit is free again for the next invoice
+    create_acked(&env, &contract, &invoice(&env, "2", &vendor, &client));
+    contract.finance_invoice(
+        &text(&env, "2"),
+        &financier,
+        &text(&env, "fin-2"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash-2"),
+    );
+    assert_eq!(contract.query_financier(&financier).exposure, 80);
+}
+
+/// Addresses whose authorization was required by the last invocation
+fn authorizers(env: &Env) -> std::vec::Vec<Address> {
+    env.auths()
+        .into_iter()
+        .map(|(address, _)| address)
+        .collect()
+}
+
+#[test]
+fn status_changes_follow_the_transition_table() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    let (action, hash) = (text(&env, "void"), text(&env, "void-hash"));
+
+    assert_eq!(
+        contract.query_allowed_transitions(&InvoiceStatus::Acknowledged),
+        vec![
+            &env,
+            InvoiceStatus::Financed,
+            InvoiceStatus::PartiallyPaid,
+            InvoiceStatus::Paid,
+            InvoiceStatus::Rejected,
+            InvoiceStatus::Voided,
+            InvoiceStatus::Disputed,
+            InvoiceStatus::Credited,
+        ]
+    );
+    assert!(contract
+        .query_allowed_transitions(&InvoiceStatus::Voided)
+        .is_empty());
+
+    // An acknowledged invoice can still be voided, and a voided one takes no payment
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    contract.void_invoice(&id, &action, &hash);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Voided);
+    assert_eq!(
+        contract.try_pay_invoice(
+            &id,
+            &client,
+            &99,
+            &text(&env, "ref"),
+            &text(&env, "pay"),
+            &text(&env, "pay-hash"),
+        ),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert_eq!(
+        contract.try_void_invoice(&id, &action, &hash),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert_eq!(token.balance(&client), 1_000);
+
+    // Paid invoices cannot be voided or acknowledged again
+    create_acked(&env, &contract, &invoice(&env, "2", &vendor, &client));
+    let id = text(&env, "2");
+    pay(&env, &contract, "2", &client, 99);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Paid);
+    assert_eq!(
+        contract.try_void_invoice(&id, &action, &hash),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert_eq!(
+        contract.try_ack_invoice(
+            &id,
+            &contract.query_revision(&id),
+            &text(&env, "ack"),
+            &text(&env, "ack-again")
+        ),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+}
+
+#[test]
+fn admin_handover_and_operator_removal() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (successor, intruder) = (Address::generate(&env), Address::generate(&env));
+    assert_eq!(
+        contract.try_accept_admin(),
+        Err(Ok(InvoiceError::NoPendingAdmin))
+    );
+
+    contract.propose_admin(&successor);
+    assert_eq!(authorizers(&env), std::vec![admin.clone()]);
+    assert_eq!(contract.query_admin(), admin);
+
+    // Only the proposed admin can accept
+    env.mock_auths(&[MockAuth {
+        address: &intruder,
+        invoke: &MockAuthInvoke {
+            contract: &contract.address,
+            fn_name: "accept_admin",
+            args: ().into_val(&env),
+            sub_invokes: &[],
+        },
+    }]);
+    assert!(contract.try_accept_admin().is_err());
+    assert_eq!(contract.query_admin(), admin);
+
+    env.mock_all_auths();
+    contract.accept_admin();
+    assert_eq!(authorizers(&env), std::vec![successor.clone()]);
+    assert_eq!(contract.query_admin(), successor);
+    assert_eq!(
+        contract.try_accept_admin(),
+        Err(Ok(InvoiceError::NoPendingAdmin))
+    );
+
+    // A removed operator can no longer update tracking
+    let operator = Address::generate(&env);
+    contract.add_operator(&operator);
+    assert_eq!(authorizers(&env), std::vec![successor.clone()]);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    let (id, empty) = (text(&env, "1"), text(&env, ""));
+    let event = text(&env, "delivered");
+    contract.update_invoice_tracking(
+        &operator, &id, &empty, &empty, &empty, &empty, &event, &empty,
+    );
+
+    contract.remove_operator(&operator);
+    assert_eq!(authorizers(&env), std::vec![successor]);
+    assert!(!contract.query_is_operator(&operator));
+    assert_eq!(
+        contract.try_update_invoice_tracking(
+            &operator, &id, &empty, &empty, &empty, &empty, &event, &empty
+        ),
+        Err(Ok(InvoiceError::Unauthorized))
+    );
+    assert_eq!(
+        contract.try_remove_operator(&operator),
+        Err(Ok(InvoiceError::OperatorNotFound))
+    );
+}
+
+#[test]
+fn mutations_require_the_acting_party() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let id = text(&env, "1");
+
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    assert_eq!(authorizers(&env), std::vec![vendor.clone()]);
+    contract.ack_invoice(&id, &0, &text(&env, "ack"), &text(&env, "ack-hash"));
+    assert_eq!(authorizers(&env), std::vec![client.clone()]);
+    contract.reject_invoice(&id, &text(&env, "reject"), &text(&env, "reject-hash"));
+    assert_eq!(authorizers(&env), std::vec![client.clone()]);
+    contract.delete_sent_invoice(
+        &id,
+        &text(&env, "gone"),
+        &text(&env, "delete"),
+        &text(&env, "delete-hash"),
+    );
+    assert_eq!(authorizers(&env), std::vec![vendor.clone()]);
+
+    contract.create_invoice(&invoice(&env, "2", &vendor, &client));
+    contract.void_invoice(
+        &text(&env, "2"),
+        &text(&env, "void"),
+        &text(&env, "void-hash"),
+    );
+    assert_eq!(authorizers(&env), std::vec![vendor]);
+}
+
+#[test]
+fn unauthorized_calls_leave_the_invoice_untouched() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+
+    // Nobody has signed anything from here on
+    env.mock_auths(&[]);
+    assert!(contract
+        .try_void_invoice(&id, &text(&env, "void"), &text(&env, "void-hash"))
+        .is_err());
+    assert!(contract
+        .try_reject_invoice(&id, &text(&env, "reject"), &text(&env, "reject-hash"))
+        .is_err());
+    assert!(contract
+        .try_pay_invoice(
+            &id,
+            &client,
+            &99,
+            &text(&env, "ref"),
+            &text(&env, "pay"),
+            &text(&env, "pay-hash"),
+        )
+        .is_err());
+    assert!(contract
+        .try_create_invoice(&invoice(&env, "2", &vendor, &client))
+        .is_err());
+
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::Acknowledged
+    );
+    assert_eq!(token.balance(&client), 1_000);
+    assert_eq!(contract.query_total_invoice_count(), 1);
+}
+
+#[test]
+fn financing_requires_a_registered_third_party() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &financier, 1_000);
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    let finance = |financier: &Address| {
+        contract.try_finance_invoice(
+            &id,
+            financier,
+            &text(&env, "fin-1"),
+            &80,
+            &5,
+            &text(&env, "finance"),
+            &text(&env, "finance-hash"),
+        )
+    };
+
+    assert_eq!(
+        finance(&financier),
+        Err(Ok(InvoiceError::FinancierNotFound))
+    );
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&client, &text(&env, "Client"), &kyc, &1_000, &1_000);
+    assert_eq!(finance(&client), Err(Ok(InvoiceError::InvalidInput)));
+    assert_eq!(token.balance(&vendor), 0);
+
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &1_000);
+    assert_eq!(authorizers(&env), std::vec![admin]);
+    assert!(finance(&financier).is_ok());
+    assert_eq!(authorizers(&env), std::vec![financier, vendor.clone()]);
+    assert_eq!(token.balance(&vendor), 80);
+}
+
+/// A filter that matches every invoice
+fn filter(env: &Env) -> InvoiceFilter {
+    InvoiceFilter {
+        statuses: Vec::new(env),
+        vendor: None,
+        client: None,
+        currency: None,
+        created_from: None,
+        created_to: None,
+        due_from: None,
+        due_to: None,
+        overdue_at: None,
+        min_amount: None,
+        max_amount: None,
+        financed: None,
+        sent_deleted: None,
+        received_deleted: None,
+    }
+}
+
+/// Every mongo_id `query` returns, following `next_cursor` to the end
+fn collect_ids(query: impl Fn(u32) -> InvoicePage) -> std::vec::Vec<std::string::String> {
+    let mut ids = std::vec::Vec::new();
+    let mut cursor = Some(0);
+    while let Some(at) = cursor {
+        let page = query(at);
+        for invoice in page.items.iter() {
+            let mut buf = [0u8; 16];
+            let len = invoice.mongo_id.len() as usize;
+            invoice.mongo_id.copy_into_slice(&mut buf[..len]);
+            ids.push(std::string::String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+        cursor = page.next_cursor;
+    }
+    ids
+}
+
+#[test]
+fn status_index_spans_pages_and_can_be_rebuilt() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let count = INDEX_PAGE_SIZE + 8;
+    for i in 0..count {
+        contract.create_invoice(&invoice(&env, &std::format!("{}", i), &vendor, &client));
+    }
+    // The first id moves out of the first page; the last id takes its slot
+    contract.ack_invoice(
+        &text(&env, "0"),
+        &0,
+        &text(&env, "ack"),
+        &text(&env, "ack-hash"),
+    );
+
+    let by_status = |status| {
+        collect_ids(|cursor| contract.query_by_status(&vendor, &status, &cursor, &MAX_PAGE_SIZE))
+    };
+    let issued = by_status(InvoiceStatus::Issued);
+    assert_eq!(issued.len() as u32, count - 1);
+    assert_eq!(issued[0], std::format!("{}", count - 1));
+    assert!(!issued.contains(&"0".into()));
+    assert_eq!(by_status(InvoiceStatus::Acknowledged), ["0"]);
+    let by_vendor = collect_ids(|cursor| {
+        contract.query_invoices(
+            &InvoiceFilter {
+                vendor: Some(vendor.clone()),
+                ..filter(&env)
+            },
+            &cursor,
+            &MAX_PAGE_SIZE,
+        )
+    });
+    assert_eq!(by_vendor.len() as u32, count);
+
+    let index = IndexKey::Status(vendor.clone(), InvoiceStatus::Issued);
+    env.as_contract(&contract.address, || {
+        env.storage()
+            .persistent()
+            .remove(&DataKey::IndexTail(index.clone()));
+    });
+    assert_eq!(
+        by_status(InvoiceStatus::Issued).len() as u32,
+        INDEX_PAGE_SIZE
+    );
+    let mut cursor = Some(0);
+    while let Some(at) = cursor {
+        cursor = contract.rebuild_index(&index, &at, &MAX_REINDEX_PAGE);
+    }
+    let rebuilt = by_status(InvoiceStatus::Issued);
+    assert_eq!(rebuilt.len() as u32, count - 1);
+    assert_eq!(rebuilt[0], "1");
+}
+
+#[test]
+fn deleted_invoices_are_hidden_from_the_deleting_side() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    contract.create_invoice(&invoice(&env, "2", &vendor, &client));
+    contract.create_invoice(&invoice(&env, "3", &vendor, &client));
+    contract.delete_sent_invoice(
+        &text(&env, "1"),
+        &text(&env, "gone"),
+        &text(&env, "delete"),
+        &text(&env, "delete-hash-1"),
+    );
+    contract.delete_received_invoice(
+        &text(&env, "2"),
+        &text(&env, "gone"),
+        &text(&env, "delete"),
+        &text(&env, "delete-hash-2"),
+    );
+
+    let query = |filter: InvoiceFilter| {
+        collect_ids(|cursor| contract.query_invoices(&filter, &cursor, &MAX_PAGE_SIZE))
+    };
+    let by_vendor = InvoiceFilter {
+        vendor: Some(vendor.clone()),
+        ..filter(&env)
+    };
+    assert_eq!(query(by_vendor.clone()), ["2", "3"]);
+    let by_client = InvoiceFilter {
+        client: Some(client.clone()),
+        ..filter(&env)
+    };
+    assert_eq!(query(by_client.clone()), ["1", "3"]);
+    assert_eq!(
+        query(InvoiceFilter {
+            sent_deleted: Some(true),
+            ..by_vendor
+        }),
+        ["1"]
+    );
+
+    assert_eq!(query(filter(&env)), ["1", "2", "3"]);
+    assert_eq!(
+        query(InvoiceFilter {
+            received_deleted: Some(false),
+            ..filter(&env)
+        }),
+        ["1", "3"]
+    );
+
+    // Views that belong to neither side leave nothing out
+    let all = collect_ids(|cursor| contract.query_all_invoices(&cursor, &MAX_PAGE_SIZE));
+    assert_eq!(all, ["1", "2", "3"]);
+    let issued = collect_ids(|cursor| {
+        contract.query_by_status(&vendor, &InvoiceStatus::Issued, &cursor, &MAX_PAGE_SIZE)
+    });
+    assert_eq!(issued, ["2", "3"]);
+    let received = collect_ids(|cursor| contract.query_by_client(&client, &cursor, &MAX_PAGE_SIZE));
+    assert_eq!(received, ["1", "3"]);
+    let by_hash = collect_ids(|cursor| {
+        contract.query_by_txnhash(&text(&env, "1-create"), &cursor, &MAX_PAGE_SIZE)
+    });
+    assert_eq!(by_hash, ["1"]);
+}
+
+#[test]
+fn invoice_filter_matches_each_field() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    contract.set_currency(&text(&env, "EUR"), &2);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &financier, 1_000);
+    let base = |id: &str| invoice(&env, id, &vendor, &client);
+    contract.create_invoice(&base("base"));
+    contract.create_invoice(&Invoice {
+        currency: text(&env, "EUR"),
+        ..base("eur")
+    });
+    contract.create_invoice(&Invoice {
+        creation_date: CREATED - 10 * DAY_IN_SECONDS,
+        ..base("early")
+    });
+    contract.create_invoice(&Invoice {
+        due_date: DUE + 30 * DAY_IN_SECONDS,
+        ..base("late")
+    });
+    contract.create_invoice(&Invoice {
+        lines: widgets(&env, 4),
+        subtotal: 200,
+        tax_total: 20,
+        net_amt: 220,
+        ..base("big")
+    });
+    create_acked(&env, &contract, &base("financed"));
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &1_000);
+    contract.finance_invoice(
+        &text(&env, "financed"),
+        &financier,
+        &text(&env, "fin-1"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash"),
+    );
+
+    let query = |filter: InvoiceFilter| {
+        collect_ids(|cursor| contract.query_invoices(&filter, &cursor, &MAX_PAGE_SIZE))
+    };
+    let all = ["base", "eur", "early", "late", "big", "financed"];
+    let except = |left_out: &str| {
+        all.iter()
+            .filter(|id| **id != left_out)
+            .map(|id| std::string::String::from(*id))
+            .collect::<std::vec::Vec<_>>()
+    };
+    assert_eq!(query(filter(&env)), all);
+
+    let eur = InvoiceFilter {
+        currency: Some(text(&env, "EUR")),
+        ..filter(&env)
+    };
+    assert_eq!(query(eur), ["eur"]);
+    let usd = InvoiceFilter {
+        currency: Some(text(&env, "USD")),
+        ..filter(&env)
+    };
+    assert_eq!(query(usd), except("eur"));
+
+    let created_before = InvoiceFilter {
+        created_to: Some(CREATED - 1),
+        ..filter(&env)
+    };
+    assert_eq!(query(created_before), ["early"]);
+    let created_since = InvoiceFilter {
+        created_from: Some(CREATED),
+        ..filter(&env)
+    };
+    assert_eq!(query(created_since), except("early"));
+
+    let due_after = InvoiceFilter {
+        due_from: Some(DUE + 1),
+        ..filter(&env)
+    };
+    assert_eq!(query(due_after), ["late"]);
+    let due_by = InvoiceFilter {
+        due_to: Some(DUE),
+        ..filter(&env)
+    };
+    assert_eq!(query(due_by), except("late"));
+
+    // Overdue means strictly past the due date
+    let at_due = InvoiceFilter {
+        overdue_at: Some(DUE),
+        ..filter(&env)
+    };
+    assert!(query(at_due).is_empty());
+    let past_due = InvoiceFilter {
+        overdue_at: Some(DUE + 1),
+        ..filter(&env)
+    };
+    assert_eq!(query(past_due), except("late"));
+
+    let above = InvoiceFilter {
+        min_amount: Some(100),
+        ..filter(&env)
+    };
+    assert_eq!(query(above), ["big"]);
+    let exactly = InvoiceFilter {
+        min_amount: Some(99),
+        max_amount: Some(99),
+        ..filter(&env)
+    };
+    assert_eq!(query(exactly), except("big"));
+
+    let financed = InvoiceFilter {
+        financed: Some(true),
+        ..filter(&env)
+    };
+    assert_eq!(query(financed), ["financed"]);
+    let unfinanced = InvoiceFilter {
+        financed: Some(false),
+        ..filter(&env)
+    };
+    assert_eq!(query(unfinanced), except("financed"));
+}
+
+#[test]
+fn private_invoices_carry_only_commitments() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    contract.set_privacy_mode(&true);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    assert_eq!(
+        contract.try_create_invoice(&invoice(&env, "1", &vendor, &client)),
+        Err(Ok(InvoiceError::PlaintextPiiNotAllowed))
+    );
+
+    let salt = Bytes::from_slice(&env, b"salt");
+    let mut preimage = salt.clone();
+    preimage.extend_from_slice(b"vendor@example.com");
+    let commitment: BytesN<32> = env.crypto().sha256(&preimage).into();
+    let empty = text(&env, "");
+    let mut private = Invoice {
+        vendor_email: empty.clone(),
+        vendor_mobile: empty.clone(),
+        client_fname: empty.clone(),
+        client_lname: empty.clone(),
+        client_email: empty.clone(),
+        client_mobile: empty.clone(),
+        privacy: Privacy::Private(PiiCommitments {
+            vendor_email: commitment.clone(),
+            vendor_mobile: commitment.clone(),
+            client_fname: commitment.clone(),
+            client_lname: commitment.clone(),
+            client_email: commitment.clone(),
+            client_mobile: commitment,
+            blob_ref: text(&env, "ipfs://blob"),
+        }),
+        ..invoice(&env, "1", &vendor, &client)
+    };
+    // The contact hashes are derived from the plaintext and would link the invoice to it
+    assert_eq!(
+        contract.try_create_invoice(&private),
+        Err(Ok(InvoiceError::PlaintextPiiNotAllowed))
+    );
+    private.vendor_email_hash = empty.clone();
+    private.vendor_mobile_hash = empty;
+    contract.create_invoice(&private);
+
+    let id = text(&env, "1");
+    let email = PiiField::VendorEmail;
+    assert!(contract.verify_pii(&id, &email, &text(&env, "vendor@example.com"), &salt));
+    assert!(!contract.verify_pii(&id, &email, &text(&env, "other@example.com"), &salt));
+    let by_email = contract.query_by_vendor_emailhash(&text(&env, ""), &0, &MAX_PAGE_SIZE);
+    assert!(by_email.items.is_empty());
+}
+
+/// Assert the last invocation fit in one transaction's ledger entry limits
+// Mainnet per-transaction limits besides the entry counts in lib.rs: `txMaxInstructions`,
+// `txMemoryLimit`, `txMaxReadBytes`, `txMaxWriteBytes` and `txMaxSizeBytes`. The contract
+// runs natively in these tests, so instruction counts understate a wasm run; the margin
+// asserted below leaves room for that.
+const TX_INSTRUCTIONS: i64 = 100_000_000;
+const TX_MEMORY_BYTES: i64 = 41_943_040;
+const TX_READ_BYTES: u32 = 200_000;
+const TX_WRITE_BYTES: u32 = 132_096;
+const TX_SIZE_BYTES: u32 = 132_096;
+
+fn assert_within_tx_limits(env: &Env) {
+    let resources = env.cost_estimate().resources();
+    assert!(
+        resources.read_entries + resources.write_entries <= TX_READ_ENTRIES
+            && resources.write_entries <= TX_WRITE_ENTRIES
+            && resources.instructions <= TX_INSTRUCTIONS / 4
+            && resources.mem_bytes <= TX_MEMORY_BYTES / 4
+            && resources.read_bytes <= TX_READ_BYTES
+            && resources.write_bytes <= TX_WRITE_BYTES,
+        "{:?}",
+        resources
+    );
+}
+
+/// The encoded arguments alone must leave room in a transaction for its envelope,
+/// signatures and footprint
+fn assert_fits_tx_size(env: &Env, args: impl ToXdr) {
+    let size = args.to_xdr(env).len();
+    assert!(size <= TX_SIZE_BYTES / 2, "{} bytes of arguments", size);
+}
+
+#[test]
+fn full_credit_settles_the_invoice() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    pay(&env, &contract, "1", &client, 40);
+
+    // 99 invoiced, 40 paid: a credit of 59 clears the rest
+    let id = text(&env, "1");
+    let credit = Invoice {
+        lines: vec![
+            &env,
+            LineItem {
+                description: text(&env, "Refund"),
+                quantity: 1,
+                unit_price: 59,
+                discount: 0,
+                tax_rate: 0,
+                tax_amount: 0,
+            },
+        ],
+        subtotal: 59,
+        tax_total: 0,
+        net_amt: 59,
+        ..invoice(&env, "c1", &vendor, &client)
+    };
+    let too_much = Invoice {
+        mongo_id: text(&env, "c0"),
+        txn_hash: text(&env, "c0-create"),
+        ..invoice(&env, "c0", &vendor, &client)
+    };
+    assert_eq!(
+        contract.try_create_credit_note(&id, &too_much),
+        Err(Ok(InvoiceError::InvalidAmount))
+    );
+    contract.create_credit_note(&id, &credit);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Credited);
+    assert_eq!(contract.query_outstanding(&id), 0);
+    assert_eq!(contract.query_notes(&id).len(), 1);
+    assert_eq!(contract.query_parent_invoice(&text(&env, "c1")), id);
+    assert_eq!(
+        contract.try_pay_invoice(
+            &id,
+            &client,
+            &1,
+            &text(&env, "ref"),
+            &text(&env, "pay"),
+            &text(&env, "late-pay"),
+        ),
+        Err(Ok(InvoiceError::InvalidAmount))
+    );
+
+    create_acked(&env, &contract, &invoice(&env, "2", &vendor, &client));
+    contract.create_debit_note(&text(&env, "2"), &invoice(&env, "d1", &vendor, &client));
+    assert_eq!(contract.query_outstanding(&text(&env, "2")), 198);
+    assert_eq!(
+        contract.query_invoice_status(&text(&env, "2")),
+        InvoiceStatus::Acknowledged
+    );
+    assert_eq!(
+        contract.query_invoice(&text(&env, "d1")).status,
+        InvoiceStatus::Applied
+    );
+}
+
+#[test]
+fn financed_invoices_cannot_be_credited() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    mint(&env, &token, &financier, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    contract.finance_invoice(
+        &id,
+        &financier,
+        &text(&env, "fin-1"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash"),
+    );
+    pay(&env, &contract, "1", &client, 1);
+
+    let credit = Invoice {
+        lines: widgets(&env, 1),
+        subtotal: 50,
+        tax_total: 5,
+        net_amt: 55,
+        ..invoice(&env, "c1", &vendor, &client)
+    };
+    assert_eq!(
+        contract.try_create_credit_note(&id, &credit),
+        Err(Ok(InvoiceError::InvoiceFinanced))
+    );
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::PartiallyPaid
+    );
+    assert_eq!(contract.query_outstanding(&id), 98);
+    assert_eq!(contract.query_financier(&financier).exposure, 79);
+}
+
+#[test]
+fn recurring_templates_mint_ids_nobody_else_can_take() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let end = RecurrenceEnd::AfterCount(2);
+    let first = contract.create_recurring_template(
+        &invoice(&env, "sub", &vendor, &client),
+        &DAY_IN_SECONDS,
+        &end,
+    );
+    // A second template over the same prototype id mints its own series
+    let second = contract.create_recurring_template(
+        &invoice(&env, "sub", &vendor, &client),
+        &DAY_IN_SECONDS,
+        &end,
+    );
+
+    let squat = |mongo_id: &str| Invoice {
+        txn_hash: text(&env, &std::format!("{}-squat", mongo_id)),
+        ..invoice(&env, mongo_id, &vendor, &client)
+    };
+    assert_eq!(
+        contract.try_create_invoice(&squat("sub-0-2")),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+    assert_eq!(
+        contract.try_create_invoice(&squat("sub-7-1")),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+    contract.create_invoice(&squat("sub-2"));
+    contract.create_invoice(&squat("other-0-1"));
+
+    assert_eq!(
+        contract.try_issue_due_recurring(&first),
+        Ok(Ok(text(&env, "sub-0-1")))
+    );
+    assert_eq!(
+        contract.try_issue_due_recurring(&first),
+        Err(Ok(InvoiceError::NotDue))
+    );
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp += 5 * DAY_IN_SECONDS);
+    assert_eq!(contract.issue_due_recurring(&first), text(&env, "sub-0-2"));
+    assert_eq!(contract.issue_due_recurring(&second), text(&env, "sub-1-1"));
+    assert_eq!(
+        contract.try_issue_due_recurring(&first),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert!(!contract.query_recurring_template(&first).active);
+
+    // The prefix is the vendor's own
+    let rival = Address::generate(&env);
+    assert_eq!(
+        contract.try_create_recurring_template(
+            &invoice(&env, "sub", &rival, &client),
+            &DAY_IN_SECONDS,
+            &end,
+        ),
+        Err(Ok(InvoiceError::Unauthorized))
+    );
+
+    // A cancelled template mints nothing more
+    contract.cancel_recurring_template(&second);
+    assert_eq!(authorizers(&env), std::vec![vendor.clone()]);
+    assert!(!contract.query_recurring_template(&second).active);
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp += DAY_IN_SECONDS);
+    assert_eq!(
+        contract.try_issue_due_recurring(&second),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert_eq!(
+        contract.try_cancel_recurring_template(&7),
+        Err(Ok(InvoiceError::NotFound))
+    );
+
+    let minted = contract.query_invoice(&text(&env, "sub-0-2"));
+    assert_eq!(minted.creation_date, CREATED + DAY_IN_SECONDS);
+    assert_eq!(minted.due_date, DUE + DAY_IN_SECONDS);
+    assert_eq!(contract.query_total_invoice_count(), 5);
+}
+
+#[test]
+fn amounts_and_currencies_are_bounded() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    for (currency, decimals) in [("", 2), ("USD", MAX_DECIMALS + 1)] {
+        assert_eq!(
+            contract.try_set_currency(&text(&env, currency), &decimals),
+            Err(Ok(InvoiceError::InvalidInput))
+        );
+    }
+    contract.set_currency(&text(&env, "USD"), &MAX_DECIMALS);
+    assert_eq!(
+        contract.query_currency_decimals(&text(&env, "USD")),
+        MAX_DECIMALS
+    );
+    assert_eq!(
+        contract.try_query_currency_decimals(&text(&env, "GBP")),
+        Err(Ok(InvoiceError::UnsupportedCurrency))
+    );
+    let gbp = Invoice {
+        currency: text(&env, "GBP"),
+        ..invoice(&env, "gbp", &vendor, &client)
+    };
+    assert_eq!(
+        contract.try_create_invoice(&gbp),
+        Err(Ok(InvoiceError::UnsupportedCurrency))
+    );
+
+    let priced = |id: &str, unit_price: i128, quantity: u32| Invoice {
+        lines: vec![
+            &env,
+            LineItem {
+                description: text(&env, "Item"),
+                quantity,
+                unit_price,
+                discount: 0,
+                tax_rate: 0,
+                tax_amount: 0,
+            },
+        ],
+        subtotal: unit_price * quantity as i128,
+        tax_total: 0,
+        net_amt: unit_price * quantity as i128,
+        ..invoice(&env, id, &vendor, &client)
+    };
+    for (unit_price, quantity) in [(0, 1), (-1, 1), (MAX_AMOUNT, 2)] {
+        assert_eq!(
+            contract.try_create_invoice(&priced("bad", unit_price, quantity)),
+            Err(Ok(InvoiceError::InvalidAmount))
+        );
+    }
+    contract.create_invoice(&priced("max", MAX_AMOUNT, 1));
+    assert_eq!(
+        contract.query_invoice(&text(&env, "max")).net_amt,
+        MAX_AMOUNT
+    );
+
+    // Payments are bounded the same way
+    let token = setup_token(&env, &contract, &admin);
+    mint(&env, &token, &client, 1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    for amount in [0, -5, MAX_AMOUNT + 1] {
+        assert_eq!(
+            contract.try_pay_invoice(
+                &text(&env, "1"),
+                &client,
+                &amount,
+                &text(&env, "ref"),
+                &text(&env, "pay"),
+                &text(&env, "pay-hash"),
+            ),
+            Err(Ok(InvoiceError::InvalidAmount))
+        );
+    }
+    assert_eq!(token.balance(&client), 1_000);
+}
+
+#[test]
+fn declared_totals_must_match_the_lines() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let line = |unit_price: i128, discount: i128, tax_amount: i128| LineItem {
+        description: text(&env, "Widget"),
+        quantity: 1,
+        unit_price,
+        discount,
+        tax_rate: 1000,
+        tax_amount,
+    };
+    let with = |id: &str, lines: Vec<LineItem>, subtotal: i128, tax_total: i128| Invoice {
+        lines,
+        subtotal,
+        tax_total,
+        net_amt: subtotal + tax_total,
+        ..invoice(&env, id, &vendor, &client)
+    };
+    let create = |invoice: &Invoice| contract.try_create_invoice(invoice).map(|_| ());
+
+    // 10% of 90 is 9; any other per-line tax is refused
+    let wrong_tax = with("1", vec![&env, line(100, 10, 8)], 90, 8);
+    assert_eq!(create(&wrong_tax), Err(Ok(InvoiceError::TotalsMismatch)));
+    let wrong_subtotal = with("1", vec![&env, line(100, 10, 9)], 91, 9);
+    assert_eq!(
+        create(&wrong_subtotal),
+        Err(Ok(InvoiceError::TotalsMismatch))
+    );
+    let wrong_net = Invoice {
+        net_amt: 100,
+        ..with("1", vec![&env, line(100, 10, 9)], 90, 9)
+    };
+    assert_eq!(create(&wrong_net), Err(Ok(InvoiceError::TotalsMismatch)));
+    let over_discount = with("1", vec![&env, line(100, 0, 10), line(100, 101, 0)], 99, 10);
+    assert_eq!(create(&over_discount), Err(Ok(InvoiceError::InvalidAmount)));
+    let over_rate = with(
+        "1",
+        vec![
+            &env,
+            LineItem {
+                tax_rate: 10_001,
+                ..line(100, 0, 10)
+            },
+        ],
+        100,
+        10,
+    );
+    assert_eq!(create(&over_rate), Err(Ok(InvoiceError::InvalidInput)));
+
+    // Tax rounds half up per line: 0.5 becomes 1 and 0.4 becomes 0
+    let rounded_down = with("1", vec![&env, line(5, 0, 0)], 5, 0);
+    assert_eq!(create(&rounded_down), Err(Ok(InvoiceError::TotalsMismatch)));
+    let lines = vec![&env, line(5, 0, 1), line(4, 0, 0), line(100, 10, 9)];
+    assert_eq!(create(&with("1", lines, 99, 10)), Ok(()));
+    let created = contract.query_invoice(&text(&env, "1"));
+    assert_eq!(
+        (created.subtotal, created.tax_total, created.net_amt),
+        (99, 10, 109)
+    );
+}
+
+/// `quantity` widgets at 50 with 10% tax
+fn widgets(env: &Env, quantity: u32) -> Vec<LineItem> {
+    vec![
+        env,
+        LineItem {
+            description: text(env, "Widget"),
+            quantity,
+            unit_price: 50,
+            discount: 0,
+            tax_rate: 1000,
+            tax_amount: 5 * quantity as i128,
+        },
+    ]
+}
+
+#[test]
+fn amendments_keep_credits_terms_and_schedule_consistent() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    let patch = |due_date: Option<u64>, lines: Option<Vec<LineItem>>| InvoicePatch {
+        due_date,
+        lines,
+        fund_reception: None,
+    };
+
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    assert_eq!(
+        contract.amend_invoice(
+            &id,
+            &patch(Some(DUE + DAY_IN_SECONDS), Some(widgets(&env, 3)))
+        ),
+        1
+    );
+    let amended = contract.query_invoice(&id);
+    assert_eq!(
+        (amended.net_amt, amended.due_date),
+        (165, DUE + DAY_IN_SECONDS)
+    );
+    assert_eq!(
+        contract.try_ack_invoice(&id, &0, &text(&env, "ack"), &text(&env, "ack-hash")),
+        Err(Ok(InvoiceError::RevisionMismatch))
+    );
+    assert_eq!(contract.reconstruct_invoice_at(&id, &0).net_amt, 99);
+    assert_eq!(contract.reconstruct_invoice_at(&id, &1).net_amt, 165);
+
+    // After a credit of 110 the lines cannot drop to 55
+    let credit = Invoice {
+        lines: widgets(&env, 2),
+        subtotal: 100,
+        tax_total: 10,
+        net_amt: 110,
+        ..invoice(&env, "c1", &vendor, &client)
+    };
+    contract.create_credit_note(&id, &credit);
+    assert_eq!(
+        contract.try_amend_invoice(&id, &patch(None, Some(widgets(&env, 1)))),
+        Err(Ok(InvoiceError::InvalidAmount))
+    );
+
+    // Payment terms fix the due date
+    let terms = PaymentTerms {
+        discount_rate: 200,
+        discount_period: 10 * DAY_IN_SECONDS,
+        net_period: 20 * DAY_IN_SECONDS,
+    };
+    contract.set_payment_terms(&id, &terms);
+    assert_eq!(
+        contract.try_amend_invoice(&id, &patch(Some(DUE), None)),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+    contract.amend_invoice(&id, &patch(Some(CREATED + 20 * DAY_IN_SECONDS), None));
+
+    // The due date cannot move before the last installment, unless the amount changes and
+    // the schedule is dropped with it
+    contract.create_invoice(&invoice(&env, "2", &vendor, &client));
+    let id = text(&env, "2");
+    contract.set_installments(
+        &id,
+        &vec![
+            &env,
+            Installment {
+                due_date: CREATED + DAY_IN_SECONDS,
+                amount: 50,
+                paid: 0,
+            },
+            Installment {
+                due_date: DUE,
+                amount: 49,
+                paid: 0,
+            },
+        ],
+    );
+    let earlier = Some(DUE - DAY_IN_SECONDS);
+    assert_eq!(
+        contract.try_amend_invoice(&id, &patch(earlier, None)),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+    contract.amend_invoice(&id, &patch(earlier, Some(widgets(&env, 1))));
+    assert!(contract.query_installments(&id).is_empty());
+
+    contract.ack_invoice(&id, &1, &text(&env, "ack"), &text(&env, "ack-hash"));
+    assert_eq!(
+        contract.try_amend_invoice(&id, &patch(Some(DUE), None)),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+}
+
+#[test]
+fn installments_add_up_to_the_total_after_notes() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    let schedule = |first: i128, second: i128| {
+        vec![
+            &env,
+            Installment {
+                due_date: CREATED + DAY_IN_SECONDS,
+                amount: first,
+                paid: 0,
+            },
+            Installment {
+                due_date: DUE,
+                amount: second,
+                paid: 0,
+            },
+        ]
+    };
+    contract.set_installments(&id, &schedule(50, 49));
+
+    // A debit note raises the total, so the old schedule no longer adds up
+    contract.create_debit_note(&id, &invoice(&env, "d1", &vendor, &client));
+    assert!(contract.query_installments(&id).is_empty());
+    assert_eq!(
+        contract.try_set_installments(&id, &schedule(50, 49)),
+        Err(Ok(InvoiceError::TotalsMismatch))
+    );
+    contract.set_installments(&id, &schedule(100, 98));
+    assert_eq!(contract.query_installments(&id).len(), 2);
+}
+
+#[test]
+fn full_pages_stay_within_read_limit() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    for i in 0..MAX_PAGE_SIZE + 1 {
+        contract.create_invoice(&invoice(&env, &std::format!("{}", i), &vendor, &client));
+    }
+
+    let page = contract.query_all_invoices(&0, &(MAX_PAGE_SIZE + 1));
+    assert_within_tx_limits(&env);
+    assert_eq!(page.items.len(), MAX_PAGE_SIZE);
+    assert_eq!(page.next_cursor, Some(MAX_PAGE_SIZE));
+
+    let pinned = InvoiceFilter {
+        vendor: Some(vendor.clone()),
+        statuses: vec![&env, InvoiceStatus::Issued],
+        ..filter(&env)
+    };
+    let page = contract.query_invoices(&pinned, &1, &MAX_PAGE_SIZE);
+    assert_within_tx_limits(&env);
+    assert_eq!(page.items.len(), MAX_PAGE_SIZE);
+
+    let index = IndexKey::Vendor(vendor.clone());
+    contract.rebuild_index(&index, &0, &MAX_REINDEX_PAGE);
+    assert_within_tx_limits(&env);
+    let index = IndexKey::Status(vendor.clone(), InvoiceStatus::Issued);
+    contract.rebuild_index(&index, &0, &MAX_REINDEX_PAGE);
+    assert_within_tx_limits(&env);
+    assert_eq!(
+        contract.rebuild_index(&index, &MAX_REINDEX_PAGE, &MAX_REINDEX_PAGE),
+        None
+    );
+    assert_within_tx_limits(&env);
+    let page = contract.query_by_status(&vendor, &InvoiceStatus::Issued, &1, &MAX_PAGE_SIZE);
+    assert_within_tx_limits(&env);
+    assert_eq!((page.items.len(), page.next_cursor), (MAX_PAGE_SIZE, None));
+}
+
+#[test]
+fn long_histories_verify_in_pages() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    contract.create_invoice(&invoice(&env, "1", &vendor, &client));
+    let operator = Address::generate(&env);
+    contract.add_operator(&operator);
+    let id = text(&env, "1");
+    let empty = text(&env, "");
+    for i in 0..2 * MAX_PAGE_SIZE {
+        let event = text(&env, &std::format!("event-{}", i));
+        contract.update_invoice_tracking(
+            &operator, &id, &empty, &empty, &empty, &empty, &event, &empty,
+        );
+    }
+
+    let check = contract.verify_history(&id, &0, &(2 * MAX_PAGE_SIZE));
+    assert_within_tx_limits(&env);
+    assert_eq!(check.mismatch, None);
+    assert_eq!(check.next_cursor, Some(MAX_PAGE_SIZE));
+    let check = contract.verify_history(&id, &MAX_PAGE_SIZE, &MAX_PAGE_SIZE);
+    assert_within_tx_limits(&env);
+    assert_eq!(check.next_cursor, Some(2 * MAX_PAGE_SIZE));
+    let last = contract.verify_history(&id, &(2 * MAX_PAGE_SIZE), &MAX_PAGE_SIZE);
+    assert_eq!(
+        last,
+        HistoryCheck {
+            mismatch: None,
+            next_cursor: None
+        }
+    );
+    assert_eq!(
+        contract.try_verify_history(&id, &(2 * MAX_PAGE_SIZE + 1), &MAX_PAGE_SIZE),
+        Err(Ok(InvoiceError::NotFound))
+    );
+
+    // Past states replay from the latest snapshot before them
+    let event = |i: u32| text(&env, &std::format!("event-{}", i));
+    let latest = contract.reconstruct_invoice_at(&id, &(2 * MAX_PAGE_SIZE));
+    assert_within_tx_limits(&env);
+    assert_eq!(
+        latest.to_xdr(&env),
+        contract.query_invoice(&id).to_xdr(&env)
+    );
+    for index in [
+        1,
+        HISTORY_SNAPSHOT_INTERVAL - 1,
+        HISTORY_SNAPSHOT_INTERVAL + 3,
+    ] {
+        let past = contract.reconstruct_invoice_at(&id, &index);
+        assert_eq!(past.tracking.event, event(index - 1));
+    }
+    // A history page starts from the latest snapshot before its cursor
+    let cursor = 2 * MAX_PAGE_SIZE - 3;
+    let page = contract.query_invoice_history(&id, &cursor, &(2 * MAX_PAGE_SIZE));
+    assert_within_tx_limits(&env);
+    assert_eq!((page.items.len(), page.next_cursor), (4, None));
+    assert_eq!(page.items.get(0).unwrap().tracking.event, event(cursor - 1));
+    let page = contract.query_invoice_history(&id, &1, &MAX_PAGE_SIZE);
+    assert_within_tx_limits(&env);
+    assert_eq!(page.next_cursor, Some(MAX_PAGE_SIZE + 1));
+    let page = contract.query_history_events(&id, &0, &(2 * MAX_PAGE_SIZE));
+    assert_within_tx_limits(&env);
+    assert_eq!(page.items.len(), MAX_PAGE_SIZE);
+    assert_eq!(page.items.get(0).unwrap().action, symbol_short!("create"));
+    assert_eq!(page.next_cursor, Some(MAX_PAGE_SIZE));
+
+    // Rewrite an event in place, leaving its digest as it was
+    let tampered = MAX_PAGE_SIZE + 5;
+    env.as_contract(&contract.address, || {
+        let key = DataKey::History(id.clone(), tampered);
+        let mut entry: HistoryEntry = env.storage().persistent().get(&key).unwrap();
+        entry.event.txn_hash = text(&env, "forged");
+        env.storage().persistent().set(&key, &entry);
+    });
+    assert_eq!(
+        contract.verify_history(&id, &0, &MAX_PAGE_SIZE).mismatch,
+        None
+    );
+    let check = contract.verify_history(&id, &MAX_PAGE_SIZE, &MAX_PAGE_SIZE);
+    assert_eq!(
+        check,
+        HistoryCheck {
+            mismatch: Some(tampered),
+            next_cursor: None
+        }
+    );
+}
+
+#[test]
+fn batches_fit_a_transaction() {
+    let env = Env::default();
+    let (contract, _) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+
+    // Every invoice brings its own client and vendor details, so no index page is shared
+    let vendor = Address::generate(&env);
+    let mut ids = std::vec::Vec::new();
+    let mut batch = Vec::new(&env);
+    for i in 0..MAX_CREATE_BATCH {
+        let id = std::format!("new-{}", i);
+        let mut item = invoice(&env, &id, &vendor, &Address::generate(&env));
+        item.vendor_id = text(&env, &id);
+        item.vendor_email_hash = text(&env, &id);
+        item.vendor_mobile_hash = text(&env, &id);
+        batch.push_back(item);
+        ids.push(id);
+    }
+    let results = contract.create_invoices(&batch);
+    assert_within_tx_limits(&env);
+    assert_fits_tx_size(&env, batch);
+    assert!(results.iter().all(|result| result.error.is_none()));
+
+    let other = Address::generate(&env);
+    let mixed = vec![
+        &env,
+        invoice(&env, "mixed-0", &vendor, &other),
+        invoice(&env, "mixed-1", &other, &vendor),
+    ];
+    let errors = contract
+        .create_invoices(&mixed)
+        .iter()
+        .map(|result| result.error);
+    assert_eq!(
+        errors.collect::<std::vec::Vec<_>>(),
+        [None, Some(InvoiceError::Unauthorized as u32)]
+    );
+
+    // Acknowledged invoices of different vendors and clients, each moved out of the middle
+    // of its vendor's status list
+    let mut acks = Vec::new(&env);
+    for i in 0..MAX_ACK_BATCH {
+        let vendor = Address::generate(&env);
+        for j in 0..3 {
+            let id = std::format!("ack-{}-{}", i, j);
+            contract.create_invoice(&invoice(&env, &id, &vendor, &Address::generate(&env)));
+            ids.push(id);
+        }
+        acks.push_back(AckRequest {
+            mongo_id: text(&env, &std::format!("ack-{}-0", i)),
+            revision: 0,
+            action: text(&env, "ack"),
+            txn_hash: text(&env, &std::format!("ack-{}", i)),
+        });
+    }
+    let results = contract.ack_invoices(&acks);
+    assert_within_tx_limits(&env);
+    assert_fits_tx_size(&env, acks);
+    assert!(results.iter().all(|result| result.error.is_none()));
+
+    let operator = Address::generate(&env);
+    contract.add_operator(&operator);
+    let mut updates = Vec::new(&env);
+    for mongo_id in ids.iter().take(MAX_TRACKING_BATCH as usize) {
+        let mut tracking = invoice(&env, "", &vendor, &vendor).tracking;
+        tracking.event = text(&env, "delivered");
+        updates.push_back(TrackingUpdate {
+            mongo_id: text(&env, mongo_id),
+            tracking,
+        });
+    }
+    assert_eq!(updates.len(), MAX_TRACKING_BATCH);
+    let results = contract.update_tracking_batch(&operator, &updates);
+    assert_within_tx_limits(&env);
+    assert_fits_tx_size(&env, updates);
+    assert!(results.iter().all(|result| result.error.is_none()));
+}
+
+fn legacy_invoice(env: &Env, mongo_id: &str, net_amt: &str, paid: bool) -> LegacyInvoice {
+    let base = invoice(
+        env,
+        mongo_id,
+        &Address::generate(env),
+        &Address::generate(env),
+    );
+    LegacyInvoice {
+        inv_type: text(env, "Invoice"),
+        vendor_id: base.vendor_id,
+        mongo_id: base.mongo_id,
+        creation_date: text(env, "1700000000"),
+        vendor_email: base.vendor_email,
+        action: text(env, if paid { "paid" } else { "create" }),
+        ack: paid,
+        finance: false,
+        financing_details: Vec::new(env),
+        vendor_email_hash: base.vendor_email_hash,
+        vendor_mobile_hash: base.vendor_mobile_hash,
+        vendor_mobile: base.vendor_mobile,
+        client_fname: base.client_fname,
+        client_lname: base.client_lname,
+        vendor_name: base.vendor_name,
+        client_email: base.client_email,
+        client_mobile: base.client_mobile,
+        currency: text(env, "USD"),
+        fund_reception: text(env, "IBAN 0000"),
+        lines: text(env, "[{\"item\":\"Widget\",\"qty\":2}]"),
+        net_amt: text(env, net_amt),
+        paid,
+        rejected: false,
+        voided: false,
+        sent_invoice_deleted: false,
+        received_invoice_deleted: false,
+        timestamp: CREATED,
+        previous_invoice_hash: text(env, ""),
+        txn_hash: base.txn_hash,
+        due_date: text(env, "2023-12-15"),
+        deleted_comments: text(env, ""),
+        payment_confirmation: false,
+        tracking: base.tracking,
+    }
+}
+
+#[test]
+fn legacy_storage_migrates_in_batches() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let (vendor, client) = (Address::generate(&env), Address::generate(&env));
+    env.as_contract(&contract.address, || {
+        let storage = env.storage().instance();
+        let mut invoices = Map::new(&env);
+        let mut history = Map::new(&env);
+        for (id, amount) in [("a", "99.50"), ("b", "10"), ("c", "7.25")] {
+            let issued = legacy_invoice(&env, id, amount, false);
+            let paid = legacy_invoice(&env, id, amount, true);
+            invoices.set(text(&env, id), paid.clone());
+            history.set(text(&env, id), vec![&env, issued, paid]);
+        }
+        storage.set(&INVOICE_MAP, &invoices);
+        storage.set(&INVOICE_HISTORY_MAP, &history);
+    });
+    let parties = |ids: &[&str]| {
+        let mut parties = Vec::new(&env);
+        for id in ids {
+            parties.push_back(LegacyParties {
+                mongo_id: text(&env, id),
+                vendor: vendor.clone(),
+                client: client.clone(),
+            });
+        }
+        parties
+    };
+
+    assert_eq!(
+        contract.try_migrate_storage(&0, &parties(&["a"])),
+        Err(Ok(InvoiceError::UnsupportedCurrency))
+    );
+    contract.set_currency(&text(&env, "USD"), &2);
+    assert_eq!(
+        contract.try_migrate_storage(&0, &parties(&["b"])),
+        Err(Ok(InvoiceError::InvalidInput))
+    );
+
+    assert_eq!(contract.migrate_storage(&0, &parties(&["a", "b"])), Some(2));
+    assert_eq!(authorizers(&env), [admin]);
+    env.as_contract(&contract.address, || {
+        assert!(env.storage().instance().has(&INVOICE_MAP));
+    });
+    assert_eq!(contract.migrate_storage(&2, &parties(&["c"])), None);
+    env.as_contract(&contract.address, || {
+        assert!(!env.storage().instance().has(&INVOICE_MAP));
+        assert!(!env.storage().instance().has(&INVOICE_HISTORY_MAP));
+    });
+    assert_eq!(
+        contract.try_migrate_storage(&0, &parties(&["a"])),
+        Err(Ok(InvoiceError::NotFound))
+    );
+
+    let migrated = contract.query_invoice(&text(&env, "a"));
+    assert_eq!((migrated.vendor, migrated.client), (vendor.clone(), client));
+    assert_eq!(migrated.status, InvoiceStatus::Paid);
+    assert_eq!((migrated.net_amt, migrated.amount_paid), (9_950, 9_950));
+    assert_eq!(migrated.creation_date, 1_700_000_000);
+    assert_eq!(migrated.due_date, CREATED);
+    assert_eq!(migrated.lines.len(), 1);
+    assert_eq!(contract.query_invoice(&text(&env, "c")).net_amt, 725);
+    assert_eq!(contract.query_total_invoice_count(), 3);
+    let id = text(&env, "a");
+    assert_eq!(
+        contract
+            .query_history_events(&id, &0, &MAX_PAGE_SIZE)
+            .items
+            .len(),
+        2
+    );
+    assert_eq!(
+        contract.verify_history(&id, &0, &MAX_PAGE_SIZE).mismatch,
+        None
+    );
+    assert_eq!(
+        contract
+            .query_by_status(&vendor, &InvoiceStatus::Paid, &0, &MAX_PAGE_SIZE)
+            .items
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn disputes_settle_by_offer_or_ruling() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    contract.set_currency(&text(&env, "USD"), &0);
+    let (vendor, client, arbiter) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    let evidence = BytesN::from_array(&env, &[1; 32]);
+
+    assert_eq!(
+        contract.try_open_dispute(&id, &vendor, &7, &evidence),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    contract.open_dispute(&id, &client, &7, &evidence);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Disputed);
+    assert_eq!(
+        contract.try_counter_offer(&id, &client, &100),
+        Err(Ok(InvoiceError::InvalidAmount))
+    );
+    contract.counter_offer(&id, &client, &80);
+    assert_eq!(
+        contract.try_accept_offer(&id, &client),
+        Err(Ok(InvoiceError::Unauthorized))
+    );
+    contract.counter_offer(&id, &vendor, &90);
+    contract.accept_offer(&id, &client);
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::Acknowledged
+    );
+    assert_eq!(contract.query_outstanding(&id), 90);
+
+    contract.open_dispute(&id, &client, &8, &evidence);
+    contract.escalate_dispute(&id, &vendor);
+    assert_eq!(
+        contract.try_rule_dispute(&id, &50),
+        Err(Ok(InvoiceError::ArbiterNotFound))
+    );
+    assert_eq!(
+        contract.try_assign_arbiter(&id, &arbiter),
+        Err(Ok(InvoiceError::ArbiterNotFound))
+    );
+    contract.add_arbiter(&arbiter);
+    contract.assign_arbiter(&id, &arbiter);
+    assert_eq!(authorizers(&env), std::vec![admin]);
+    contract.rule_dispute(&id, &50);
+    assert_eq!(authorizers(&env), std::vec![arbiter.clone()]);
+    assert_eq!(contract.query_invoice(&id).adjustment, -49);
+    assert_eq!(contract.query_dispute(&id).state, DisputeState::Resolved);
+    assert_eq!(
+        contract.verify_history(&id, &0, &MAX_PAGE_SIZE).mismatch,
+        None
+    );
+
+    // Every dispute step records the dispute as it stood afterwards
+    let events = contract.query_history_events(&id, &0, &MAX_PAGE_SIZE).items;
+    let recorded = |index: u32| {
+        events
+            .get(index)
+            .unwrap()
+            .changes
+            .iter()
+            .find_map(|change| match change {
+                InvoiceChange::Dispute(dispute) => Some(dispute),
+                _ => None,
+            })
+            .unwrap()
+    };
+    let opened = recorded(2);
+    assert_eq!((opened.reason_code, opened.evidence_hash), (7, evidence));
+    let countered = recorded(4);
+    assert_eq!(
+        (countered.offer_amount, countered.offer_by),
+        (90, Some(vendor))
+    );
+    assert_eq!(recorded(5).state, DisputeState::Resolved);
+    let escalated = recorded(7);
+    assert_eq!(escalated.state, DisputeState::Escalated);
+    assert_eq!(escalated.arbiter, None);
+    assert_eq!(recorded(8).arbiter, Some(arbiter));
+    assert_eq!(recorded(9).state, DisputeState::Resolved);
+}
+
+#[test]
+fn financed_invoices_cannot_be_disputed() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, financier) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    mint(&env, &token, &financier, 1_000);
+    let kyc = BytesN::from_array(&env, &[0; 32]);
+    contract.register_financier(&financier, &text(&env, "Fin"), &kyc, &1_000, &1_000);
+    create_acked(&env, &contract, &invoice(&env, "1", &vendor, &client));
+    let id = text(&env, "1");
+    contract.finance_invoice(
+        &id,
+        &financier,
+        &text(&env, "fin-1"),
+        &80,
+        &5,
+        &text(&env, "finance"),
+        &text(&env, "finance-hash"),
+    );
+    pay(&env, &contract, "1", &client, 1);
+
+    let evidence = BytesN::from_array(&env, &[1; 32]);
+    assert_eq!(
+        contract.try_open_dispute(&id, &client, &7, &evidence),
+        Err(Ok(InvoiceError::InvoiceFinanced))
+    );
+    assert_eq!(
+        contract.query_invoice_status(&id),
+        InvoiceStatus::PartiallyPaid
+    );
+}
+
+#[test]
+fn vendor_can_claim_disputed_escrow() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, arbiter) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    contract.add_arbiter(&arbiter);
+    let mut escrowed = invoice(&env, "1", &vendor, &client);
+    escrowed.escrow = true;
+    create_acked(&env, &contract, &escrowed);
+    pay(&env, &contract, "1", &client, 99);
+    let id = text(&env, "1");
+    let evidence = BytesN::from_array(&env, &[2; 32]);
+
+    // The vendor disputes before the client can wait out the escrow timeout
+    contract.open_dispute(&id, &vendor, &1, &evidence);
+    env.ledger()
+        .with_mut(|ledger| ledger.timestamp += DEFAULT_ESCROW_TIMEOUT);
+    let (action, hash) = (text(&env, "refund"), text(&env, "refund-hash"));
+    assert_eq!(
+        contract.try_refund_escrow(&id, &action, &hash),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+    assert_eq!(
+        contract.try_payment_confirmation_invoice(&id, &action, &hash),
+        Err(Ok(InvoiceError::InvalidTransition))
+    );
+
+    // A ruling below the escrowed amount refunds the difference and releases the rest
+    contract.escalate_dispute(&id, &vendor);
+    contract.assign_arbiter(&id, &arbiter);
+    contract.rule_dispute(&id, &60);
+    assert_eq!(token.balance(&client), 1_000 - 60);
+    assert_eq!(token.balance(&vendor), 60);
+    assert_eq!(token.balance(&contract.address), 0);
+    let settled = contract.query_invoice(&id);
+    assert_eq!(settled.status, InvoiceStatus::PaymentConfirmed);
+    assert_eq!((settled.amount_paid, settled.adjustment), (60, -39));
+
+    let events = contract.query_history_events(&id, &0, &MAX_PAGE_SIZE).items;
+    let ruling = events.last().unwrap();
+    assert_eq!(ruling.action, symbol_short!("ruling"));
+    assert!(ruling
+        .changes
+        .contains(InvoiceChange::EscrowRefunded(client.clone(), 39)));
+    assert!(ruling
+        .changes
+        .contains(InvoiceChange::EscrowReleased(vendor.clone(), 60)));
+    assert_eq!(
+        contract.verify_history(&id, &0, &MAX_PAGE_SIZE).mismatch,
+        None
+    );
+}
+
+#[test]
+fn ruling_of_zero_refunds_all_escrow() {
+    let env = Env::default();
+    let (contract, admin) = setup(&env);
+    let token = setup_token(&env, &contract, &admin);
+    let (vendor, client, arbiter) = (
+        Address::generate(&env),
+        Address::generate(&env),
+        Address::generate(&env),
+    );
+    mint(&env, &token, &client, 1_000);
+    contract.add_arbiter(&arbiter);
+    let mut escrowed = invoice(&env, "1", &vendor, &client);
+    escrowed.escrow = true;
+    create_acked(&env, &contract, &escrowed);
+    pay(&env, &contract, "1", &client, 99);
+
+    let id = text(&env, "1");
+    contract.open_dispute(&id, &client, &3, &BytesN::from_array(&env, &[3; 32]));
+    contract.escalate_dispute(&id, &client);
+    contract.assign_arbiter(&id, &arbiter);
+    contract.rule_dispute(&id, &0);
+    assert_eq!(token.balance(&client), 1_000);
+    assert_eq!(contract.query_invoice_status(&id), InvoiceStatus::Refunded);
+    assert!(contract.try_query_escrow(&id).is_err());
+}